@@ -0,0 +1,181 @@
+use std::sync::{Arc, Mutex};
+
+use crossbeam_channel::{Receiver, Sender};
+use pulldown_cmark::{Event, Options, Parser};
+
+use crate::protocol::ClientMessage;
+
+/// Something that happened in a browser displaying the preview.
+///
+/// Events are received from [`Server::events`](crate::Server::events). Apart from connections
+/// and disconnections, events are only sent by clients that speak the
+/// [structured protocol](crate::protocol), such as the bundled preview page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ClientEvent {
+    /// A websocket client connected.
+    Connected,
+
+    /// A websocket client disconnected.
+    Disconnected,
+
+    /// A link in the preview was clicked.
+    LinkClicked {
+        /// The target of the link, as written in the markdown.
+        href: String,
+    },
+
+    /// A task list checkbox in the preview was clicked.
+    ///
+    /// The preview page makes checkboxes clickable if their list item has a `data-sourcepos`
+    /// attribute, which the server's built-in renderer adds unless [`RenderOptions::sourcepos`] is
+    /// disabled. Use [`set_task_checked`] to update the markdown accordingly.
+    ///
+    /// [`RenderOptions::sourcepos`]: crate::RenderOptions::sourcepos
+    CheckboxToggled {
+        /// The line of the markdown containing the task, numbered from 1.
+        line: usize,
+
+        /// Whether the checkbox is now checked.
+        checked: bool,
+    },
+
+    /// The user asked to jump to the source of an element in the preview.
+    ///
+    /// The preview page sends this when an element with a `data-sourcepos` attribute is
+    /// double-clicked, which the server's built-in renderer adds unless
+    /// [`RenderOptions::sourcepos`] is disabled.
+    ///
+    /// [`RenderOptions::sourcepos`]: crate::RenderOptions::sourcepos
+    JumpToSource {
+        /// The first line of the element's markdown, numbered from 1.
+        line: usize,
+    },
+}
+
+impl From<ClientMessage> for ClientEvent {
+    fn from(message: ClientMessage) -> Self {
+        match message {
+            ClientMessage::LinkClicked { href } => ClientEvent::LinkClicked { href },
+            ClientMessage::CheckboxToggled { line, checked } => {
+                ClientEvent::CheckboxToggled { line, checked }
+            }
+            ClientMessage::JumpToSource { line } => ClientEvent::JumpToSource { line },
+        }
+    }
+}
+
+/// Checks or unchecks the task list item on a line of markdown, returning the updated markdown.
+///
+/// Lines are numbered from 1, as in [`ClientEvent::CheckboxToggled`]. Returns `None` if there is
+/// no task on the line.
+///
+/// # Example
+///
+/// ```
+/// let markdown = "- [ ] write docs\n- [ ] ship it\n";
+///
+/// assert_eq!(
+///     aurelius::set_task_checked(markdown, 2, true).as_deref(),
+///     Some("- [ ] write docs\n- [x] ship it\n"),
+/// );
+/// ```
+pub fn set_task_checked(markdown: &str, line: usize, checked: bool) -> Option<String> {
+    let line_start = match line {
+        0 => return None,
+        1 => 0,
+        _ => markdown.match_indices('\n').nth(line - 2)?.0 + 1,
+    };
+    let line_end = markdown[line_start..]
+        .find('\n')
+        .map_or(markdown.len(), |end| line_start + end);
+
+    // Find the marker with the parser, so that brackets in code or in other text are ignored.
+    let marker = Parser::new_ext(markdown, Options::ENABLE_TASKLISTS)
+        .into_offset_iter()
+        .find_map(|(event, range)| match event {
+            Event::TaskListMarker(_) if (line_start..line_end).contains(&range.start) => {
+                Some(range)
+            }
+            _ => None,
+        })?;
+
+    let mut markdown = markdown.to_owned();
+    let mark = if checked { "x" } else { " " };
+    markdown.replace_range(marker.start + 1..marker.end - 1, mark);
+    Some(markdown)
+}
+
+/// The channel that client events are delivered on.
+///
+/// The channel is only created once someone subscribes, and dropped once its receiver is, so that
+/// events don't pile up in programs that don't read them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Events {
+    sender: Arc<Mutex<Option<Sender<ClientEvent>>>>,
+}
+
+impl Events {
+    pub fn subscribe(&self) -> Receiver<ClientEvent> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        *self.sender.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    pub fn emit(&self, event: ClientEvent) {
+        let mut sender = self.sender.lock().unwrap();
+
+        if let Some(tx) = &*sender {
+            if tx.send(event).is_err() {
+                *sender = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{set_task_checked, ClientEvent, Events};
+
+    #[test]
+    fn events_dropped_with_receiver() {
+        let events = Events::default();
+        events.emit(ClientEvent::Connected);
+
+        let rx = events.subscribe();
+        events.emit(ClientEvent::Connected);
+        assert_eq!(rx.try_recv(), Ok(ClientEvent::Connected));
+
+        drop(rx);
+        events.emit(ClientEvent::Disconnected);
+        assert!(events.sender.lock().unwrap().is_none());
+
+        let rx = events.subscribe();
+        events.emit(ClientEvent::Disconnected);
+        assert_eq!(rx.try_recv(), Ok(ClientEvent::Disconnected));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn set_task() {
+        let markdown = "# Tasks\r\n\r\n1. [X] one\r\n   - [ ] two\r\n> - [ ] `[ ]` three\r\n";
+
+        assert_eq!(
+            set_task_checked(markdown, 3, false).unwrap(),
+            "# Tasks\r\n\r\n1. [ ] one\r\n   - [ ] two\r\n> - [ ] `[ ]` three\r\n"
+        );
+        assert_eq!(
+            set_task_checked(markdown, 4, true).unwrap(),
+            "# Tasks\r\n\r\n1. [X] one\r\n   - [x] two\r\n> - [ ] `[ ]` three\r\n"
+        );
+        assert_eq!(
+            set_task_checked(markdown, 5, true).unwrap(),
+            "# Tasks\r\n\r\n1. [X] one\r\n   - [ ] two\r\n> - [x] `[ ]` three\r\n"
+        );
+
+        assert_eq!(set_task_checked(markdown, 0, true), None);
+        assert_eq!(set_task_checked(markdown, 1, true), None);
+        assert_eq!(set_task_checked(markdown, 6, true), None);
+        assert_eq!(set_task_checked(markdown, 100, true), None);
+    }
+}
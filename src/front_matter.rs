@@ -0,0 +1,181 @@
+use std::borrow::Cow;
+
+use log::*;
+use serde_json::{Map, Value};
+
+/// Metadata parsed from the front matter of a document.
+pub(crate) type Metadata = Map<String, Value>;
+
+/// Separates the front matter at the start of a document from its markdown.
+///
+/// Front matter is a block of YAML delimited by `---` lines (the closing line may also be `...`),
+/// or a block of TOML delimited by `+++` lines. The front matter is replaced by blank lines, so
+/// that the lines of the markdown keep their numbers. If the block can't be parsed, or isn't a
+/// table of keys and values, it is probably markdown that happens to be surrounded by thematic
+/// breaks, so the document is returned unchanged.
+pub(crate) fn split(markdown: &str) -> (Metadata, Cow<'_, str>) {
+    let (format, closing): (Format, &[&str]) = match first_line(markdown) {
+        "---" => (Format::Yaml, &["---", "..."]),
+        "+++" => (Format::Toml, &["+++"]),
+        _ => return (Metadata::new(), Cow::Borrowed(markdown)),
+    };
+
+    let body_start = markdown.find('\n').unwrap() + 1;
+    let mut line_start = body_start;
+
+    let (body_end, end) = loop {
+        if line_start >= markdown.len() {
+            // Without a closing delimiter, the document doesn't start with front matter.
+            return (Metadata::new(), Cow::Borrowed(markdown));
+        }
+
+        let line_end = markdown[line_start..]
+            .find('\n')
+            .map_or(markdown.len(), |end| line_start + end + 1);
+
+        if closing.contains(&markdown[line_start..line_end].trim_end()) {
+            break (line_start, line_end);
+        }
+
+        line_start = line_end;
+    };
+
+    let metadata = match parse(format, &markdown[body_start..body_end]) {
+        Some(metadata) => metadata,
+        None => return (Metadata::new(), Cow::Borrowed(markdown)),
+    };
+
+    let lines = markdown[..end].matches('\n').count();
+    let blanked = "\n".repeat(lines) + &markdown[end..];
+
+    (metadata, Cow::Owned(blanked))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Yaml,
+    Toml,
+}
+
+fn first_line(markdown: &str) -> &str {
+    match markdown.find('\n') {
+        Some(end) => markdown[..end].trim_end(),
+        // A lone delimiter can't be followed by a body.
+        None => "",
+    }
+}
+
+fn parse(format: Format, body: &str) -> Option<Metadata> {
+    let value = match format {
+        // An empty YAML document is null rather than an empty mapping.
+        Format::Yaml if body.trim().is_empty() => return Some(Metadata::new()),
+        Format::Yaml => serde_yaml::from_str::<Value>(body).map_err(|e| e.to_string()),
+        Format::Toml => body
+            .parse::<toml::Value>()
+            .map(toml_to_json)
+            .map_err(|e| e.to_string()),
+    };
+
+    match value {
+        Ok(Value::Object(metadata)) => Some(metadata),
+        Ok(_) => {
+            debug!("ignoring front matter that isn't a table of keys and values");
+            None
+        }
+        Err(e) => {
+            debug!("could not parse front matter: {}", e);
+            None
+        }
+    }
+}
+
+/// Converts TOML to JSON. Dates and times are converted to strings in RFC 3339 format.
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(array) => Value::Array(array.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{split, Metadata};
+
+    fn metadata(value: serde_json::Value) -> Metadata {
+        match value {
+            serde_json::Value::Object(metadata) => metadata,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn yaml() {
+        let (meta, markdown) =
+            split("---\r\ntitle: Notes\r\ntags: [a, b]\r\ndraft: false\r\n...\r\n# Notes\r\n");
+
+        assert_eq!(
+            meta,
+            metadata(json!({"title": "Notes", "tags": ["a", "b"], "draft": false}))
+        );
+        assert_eq!(markdown, "\n\n\n\n\n# Notes\r\n");
+    }
+
+    #[test]
+    fn toml() {
+        let (meta, markdown) = split("+++\ntitle = \"Notes\"\ndate = 2020-01-02\n+++\n\nText");
+
+        assert_eq!(
+            meta,
+            metadata(json!({"title": "Notes", "date": "2020-01-02"}))
+        );
+        assert_eq!(markdown, "\n\n\n\n\nText");
+    }
+
+    #[test]
+    fn invalid_front_matter() {
+        for markdown in &[
+            "---\n: [\n---\nText",
+            "---\n- a list\n---\n",
+            "---\n# Intro\nImportant paragraph\n---\n\nRest",
+            "+++\nnot toml\n+++\n",
+        ] {
+            let (meta, split) = split(markdown);
+            assert!(meta.is_empty());
+            assert_eq!(split, *markdown);
+        }
+    }
+
+    #[test]
+    fn empty_front_matter() {
+        let (meta, markdown) = split("---\n---\nText");
+        assert!(meta.is_empty());
+        assert_eq!(markdown, "\n\nText");
+    }
+
+    #[test]
+    fn no_front_matter() {
+        for markdown in &[
+            "# Title\n---\n",
+            "---\nnot closed\n",
+            "---",
+            " ---\ntitle: x\n---\n",
+            "Text\n\n---\ntitle: x\n---\n",
+        ] {
+            let (meta, split) = split(markdown);
+            assert!(meta.is_empty());
+            assert_eq!(split, *markdown);
+        }
+    }
+}
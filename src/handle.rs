@@ -0,0 +1,190 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use crossbeam_channel::Receiver;
+use pulldown_cmark::Event;
+
+use crate::events::ClientEvent;
+use crate::render::{CodeBlockHandler, ExternalRenderer, Heading, RenderOptions, Renderer};
+use crate::worker::RenderStats;
+use crate::Shared;
+
+/// A cloneable, thread-safe handle to a [`Server`].
+///
+/// Handles are created with [`Server::handle`]. They may be sent to other threads to publish
+/// markdown or change the server's settings without synchronizing access to the `Server` itself.
+///
+/// Once the `Server` is dropped, every method that communicates with it returns an error of kind
+/// [`io::ErrorKind::NotConnected`].
+///
+/// [`Server`]: crate::Server
+/// [`Server::handle`]: crate::Server::handle
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    addr: SocketAddr,
+    shared: Weak<Shared>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(addr: SocketAddr, shared: Weak<Shared>) -> Self {
+        ServerHandle { addr, shared }
+    }
+
+    /// Returns the socket address that the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns `true` if the server has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.strong_count() == 0
+    }
+
+    /// Publish new markdown to be rendered by the server.
+    ///
+    /// See [`Server::send`](crate::Server::send).
+    pub fn send(&self, markdown: String) -> io::Result<()> {
+        self.shared()?.send(markdown)
+    }
+
+    /// Scroll all connected browsers to a line of the markdown.
+    ///
+    /// See [`Server::scroll_to_line`](crate::Server::scroll_to_line).
+    pub fn scroll_to_line(&self, line: usize) -> io::Result<()> {
+        self.shared()?.scroll_to_line(line);
+        Ok(())
+    }
+
+    /// Returns a channel of events that happen in the connected browsers.
+    ///
+    /// See [`Server::events`](crate::Server::events).
+    pub fn events(&self) -> io::Result<Receiver<ClientEvent>> {
+        Ok(self.shared()?.events.subscribe())
+    }
+
+    /// Returns the outline of the most recently rendered markdown.
+    ///
+    /// See [`Server::outline`](crate::Server::outline).
+    pub fn outline(&self) -> io::Result<Vec<Heading>> {
+        Ok(self.shared()?.outline())
+    }
+
+    /// Returns the metadata from the front matter of the most recently rendered markdown.
+    ///
+    /// See [`Server::metadata`](crate::Server::metadata).
+    pub fn metadata(&self) -> io::Result<serde_json::Map<String, serde_json::Value>> {
+        Ok(self.shared()?.metadata())
+    }
+
+    /// Returns counters describing the work done by the renderer.
+    ///
+    /// See [`Server::render_stats`](crate::Server::render_stats).
+    pub fn render_stats(&self) -> io::Result<RenderStats> {
+        Ok(*self.shared()?.stats.lock().unwrap())
+    }
+
+    /// Returns the number of websocket clients that are currently connected.
+    pub fn client_count(&self) -> io::Result<usize> {
+        Ok(self.shared()?.client_count())
+    }
+
+    /// Set how long the server waits for more markdown before rendering.
+    ///
+    /// See [`Server::set_debounce`](crate::Server::set_debounce).
+    pub fn set_debounce(&self, debounce: Duration) -> io::Result<()> {
+        self.shared()?.set_debounce(debounce);
+        Ok(())
+    }
+
+    /// Set the markdown extensions used by the built-in renderer.
+    ///
+    /// See [`Server::set_render_options`](crate::Server::set_render_options).
+    pub fn set_render_options(&self, options: RenderOptions) -> io::Result<()> {
+        self.shared()?.set_render_options(options)
+    }
+
+    /// Render fenced code blocks in a language by piping them through a command.
+    ///
+    /// See [`Server::add_fence_command`](crate::Server::add_fence_command).
+    pub fn add_fence_command(
+        &self,
+        language: impl Into<String>,
+        command: Command,
+    ) -> io::Result<()> {
+        self.shared()?.add_fence_command(language.into(), command)
+    }
+
+    /// Render fenced code blocks with an in-process handler.
+    ///
+    /// See [`Server::add_code_block_handler`](crate::Server::add_code_block_handler).
+    pub fn add_code_block_handler(&self, handler: Box<dyn CodeBlockHandler>) -> io::Result<()> {
+        self.shared()?.add_code_block_handler(handler)
+    }
+
+    /// Add a hook that maps or filters the markdown events before they are written as HTML.
+    ///
+    /// See [`Server::add_transform`](crate::Server::add_transform).
+    pub fn add_transform<F>(&self, transform: F) -> io::Result<()>
+    where
+        F: for<'a> FnMut(Event<'a>) -> Option<Event<'a>> + Send + 'static,
+    {
+        self.shared()?.add_transform(transform)
+    }
+
+    /// Set the directory that static files will be served from.
+    ///
+    /// See [`Server::set_static_root`](crate::Server::set_static_root).
+    pub fn set_static_root(&self, root: impl Into<PathBuf>) -> io::Result<()> {
+        self.shared()?.set_static_root(root.into());
+        Ok(())
+    }
+
+    /// Set the highlight.js theme used for code blocks.
+    ///
+    /// See [`Server::set_highlight_theme`](crate::Server::set_highlight_theme).
+    pub fn set_highlight_theme(&self, theme: String) -> io::Result<()> {
+        self.shared()?.set_highlight_theme(theme)
+    }
+
+    /// Set whether code blocks are highlighted by the server instead of the browser.
+    ///
+    /// See `Server::set_server_side_highlighting`. Requires the `server-highlighting` feature.
+    #[cfg(feature = "server-highlighting")]
+    pub fn set_server_side_highlighting(&self, enabled: bool) -> io::Result<()> {
+        self.shared()?.set_server_side_highlighting(enabled)
+    }
+
+    /// Set custom CSS links and files to be served with the rendered HTML.
+    ///
+    /// See [`Server::set_custom_css`](crate::Server::set_custom_css).
+    pub fn set_custom_css(&self, stylesheets: Vec<String>) -> io::Result<()> {
+        self.shared()?.set_custom_css(stylesheets)
+    }
+
+    /// Set an external program to use for rendering the markdown.
+    ///
+    /// See [`Server::set_external_renderer`](crate::Server::set_external_renderer).
+    pub fn set_external_renderer(&self, command: Command) -> io::Result<()> {
+        self.set_renderer(Box::new(ExternalRenderer::new(command)))
+    }
+
+    /// Set a custom renderer to use for rendering the markdown.
+    ///
+    /// See [`Server::set_renderer`](crate::Server::set_renderer).
+    pub fn set_renderer(&self, renderer: Box<dyn Renderer>) -> io::Result<()> {
+        self.shared()?.set_renderer(renderer)
+    }
+
+    fn shared(&self) -> io::Result<Arc<Shared>> {
+        self.shared.upgrade().ok_or_else(closed)
+    }
+}
+
+/// The error returned when communicating with a server that has been dropped.
+pub(crate) fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "the server has been dropped")
+}
@@ -23,6 +23,10 @@ impl<T> IdMap<T> {
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.values().count()
+    }
+
     pub fn values(&self) -> impl Iterator<Item = &T> {
         self.storage.iter().flatten()
     }
@@ -15,12 +15,12 @@
 //! ```no_run
 //! use aurelius::Server;
 //!
-//! let mut server = Server::bind("localhost:0")?;
+//! let server = Server::bind("localhost:0")?;
 //! println!("listening on {}", server.addr());
 //!
 //! server.open_browser()?;
 //!
-//! server.send(String::from("# Hello, world"));
+//! server.send(String::from("# Hello, world"))?;
 //! # Ok::<_, Box<dyn std::error::Error>>(())
 //! ```
 //!
@@ -53,23 +53,47 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use buf_redux::BufReader;
-use crossbeam_channel::{select, Sender};
+use crossbeam_channel::{select, Receiver, Sender};
 use crossbeam_utils::thread as crossbeam_thread;
 use handlebars::Handlebars;
 use httparse::{Request, Status, EMPTY_HEADER};
 use include_dir::{include_dir, Dir};
 use log::*;
-use pulldown_cmark::{Options, Parser};
+use pulldown_cmark::Event;
 use serde::Serialize;
 use sha1::{Digest, Sha1};
 use tungstenite::{protocol::Role, Message, WebSocket};
 use url::Url;
 
+use crate::events::Events;
+use crate::front_matter::Metadata;
 use crate::id_map::IdMap;
-
+use crate::protocol::{ClientMessage, ServerMessage};
+use crate::worker::{Job, RenderWorker, Renderers};
+
+pub use crate::events::{set_task_checked, ClientEvent};
+pub use crate::handle::ServerHandle;
+pub use crate::render::{
+    CodeBlockHandler, ExternalRenderer, Framing, Heading, MarkdownRenderer, PersistentRenderer,
+    RenderError, RenderOptions, Renderer,
+};
+pub use crate::worker::RenderStats;
+
+/// The markdown parser used by [`MarkdownRenderer`], whose events are passed to
+/// [`Server::add_transform`].
+pub use pulldown_cmark;
+
+pub mod protocol;
+
+mod events;
+mod front_matter;
+mod handle;
 mod id_map;
+mod render;
+mod worker;
 
 const STATIC_FILES: Dir = include_dir!("static");
 
@@ -80,10 +104,10 @@ const STATIC_FILES: Dir = include_dir!("static");
 #[derive(Debug)]
 pub struct Server {
     addr: SocketAddr,
-    config: Arc<Mutex<Config>>,
-    external_renderer: Option<Command>,
-    md_clients: Arc<Mutex<IdMap<Sender<Signal>>>>,
-    html: Arc<RwLock<Option<String>>>,
+    /// State shared with [`ServerHandle`]s.
+    ///
+    /// The server holds the only strong reference, so handles stop working once it is dropped.
+    shared: Arc<Shared>,
     /// Indicates whether the server should initiate shutdown.
     ///
     /// On drop, we want the server to clean up existing connections gracefully and stop listening
@@ -93,6 +117,7 @@ pub struct Server {
     /// then immediately opens a connection.
     shutdown: Arc<AtomicBool>,
     listener_join_handle: Option<JoinHandle<()>>,
+    worker_join_handle: Option<JoinHandle<()>>,
 }
 
 impl Server {
@@ -110,11 +135,39 @@ impl Server {
         let md_clients = Arc::new(Mutex::new(IdMap::default()));
         let config = Arc::new(Mutex::new(Config::default()));
         let html = Arc::new(RwLock::new(None));
+        let outline = Arc::new(RwLock::new(Arc::new(vec![])));
+        let metadata = Arc::new(RwLock::new(Arc::new(Metadata::new())));
+        let math_delimiters = Arc::new(AtomicBool::new(false));
+        let events = Events::default();
+        let stats = Arc::new(Mutex::new(RenderStats::default()));
+
+        let (jobs, jobs_rx) = crossbeam_channel::unbounded();
+
+        let worker = RenderWorker {
+            jobs: jobs_rx,
+            config: Arc::clone(&config),
+            md_clients: Arc::clone(&md_clients),
+            html: Arc::clone(&html),
+            outline: Arc::clone(&outline),
+            metadata: Arc::clone(&metadata),
+            math_delimiters: Arc::clone(&math_delimiters),
+            stats: Arc::clone(&stats),
+            renderers: Default::default(),
+            markdown: None,
+            rendered_markdown: None,
+            rendered_html: None,
+        };
+
+        let worker_join_handle = thread::spawn(move || worker.run());
 
         let conn_shutdown = Arc::clone(&shutdown);
         let conn_md_clients = Arc::clone(&md_clients);
         let conn_config = Arc::clone(&config);
         let conn_html = Arc::clone(&html);
+        let conn_outline = Arc::clone(&outline);
+        let conn_metadata = Arc::clone(&metadata);
+        let conn_math_delimiters = Arc::clone(&math_delimiters);
+        let conn_events = events.clone();
 
         let join_handle = thread::spawn(move || {
             crossbeam_thread::scope(|s| {
@@ -131,6 +184,10 @@ impl Server {
                     let handler_config = Arc::clone(&conn_config);
                     let handler_md_clients = Arc::clone(&conn_md_clients);
                     let handler_html = Arc::clone(&conn_html);
+                    let handler_outline = Arc::clone(&conn_outline);
+                    let handler_metadata = Arc::clone(&conn_metadata);
+                    let handler_math_delimiters = Arc::clone(&conn_math_delimiters);
+                    let handler_events = conn_events.clone();
 
                     s.spawn(|_| {
                         let handler = Handler {
@@ -138,6 +195,10 @@ impl Server {
                             config: handler_config,
                             md_clients: handler_md_clients,
                             html: handler_html,
+                            outline: handler_outline,
+                            metadata: handler_metadata,
+                            math_delimiters: handler_math_delimiters,
+                            events: handler_events,
                         };
 
                         if let Err(e) = handler.handle() {
@@ -161,12 +222,18 @@ impl Server {
 
         Ok(Server {
             addr,
-            config,
-            md_clients,
-            html,
-            external_renderer: None,
+            shared: Arc::new(Shared {
+                config,
+                md_clients,
+                outline,
+                metadata,
+                events,
+                stats,
+                jobs,
+            }),
             shutdown,
             listener_join_handle: Some(join_handle),
+            worker_join_handle: Some(worker_join_handle),
         })
     }
 
@@ -177,44 +244,225 @@ impl Server {
 
     /// Publish new markdown to be rendered by the server.
     ///
-    /// The new HTML will be sent to all connected websocket clients.
+    /// The markdown is rendered on a background thread, so this method returns immediately. Once
+    /// rendering is complete, the new HTML will be sent to all connected websocket clients. If
+    /// more markdown is published while a render is in progress, only the most recent markdown
+    /// will be rendered next.
+    ///
+    /// Errors that occur while rendering are logged and reported to the clients, which keep
+    /// displaying the last successful render.
     ///
     /// # Errors
     ///
-    /// This method forwards errors from an external renderer, if set. Otherwise, the method is
-    /// infallible.
-    pub fn send(&mut self, markdown: String) -> io::Result<()> {
-        let html = if let Some(renderer) = &mut self.external_renderer {
-            let child = renderer.spawn()?;
+    /// Returns an error if the render worker has exited.
+    pub fn send(&self, markdown: String) -> io::Result<()> {
+        self.shared.send(markdown)
+    }
 
-            child.stdin.unwrap().write_all(markdown.as_bytes())?;
+    /// Scroll all connected browsers to a line of the markdown.
+    ///
+    /// Lines are numbered from 1. The browser scrolls to the last block that starts at or before
+    /// the line, so this requires the rendered HTML to have `data-sourcepos` attributes. The
+    /// built-in renderer adds them unless [`RenderOptions::sourcepos`] is disabled; see
+    /// [`Renderer`] for other renderers.
+    ///
+    /// This is typically called whenever the cursor moves in the editor. Only clients that speak
+    /// the [structured protocol](protocol) receive scroll commands.
+    pub fn scroll_to_line(&self, line: usize) {
+        self.shared.scroll_to_line(line);
+    }
 
-            let mut html = String::with_capacity(markdown.len());
-            child.stdout.unwrap().read_to_string(&mut html)?;
+    /// Returns a handle to the server that can be shared between threads.
+    ///
+    /// Handles are cheap to clone and may be used to publish markdown or change settings from any
+    /// thread. Once the server is dropped, all operations on its handles fail.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::thread;
+    /// use aurelius::Server;
+    ///
+    /// let server = Server::bind("localhost:0")?;
+    /// let handle = server.handle();
+    ///
+    /// thread::spawn(move || {
+    ///     handle.send(String::from("# Hello from another thread"))
+    /// });
+    /// # Ok::<_, Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(self.addr, Arc::downgrade(&self.shared))
+    }
 
-            html
-        } else {
-            let mut html = String::with_capacity(markdown.len());
-            let parser = Parser::new_ext(
-                &markdown,
-                Options::ENABLE_FOOTNOTES
-                    | Options::ENABLE_TABLES
-                    | Options::ENABLE_STRIKETHROUGH
-                    | Options::ENABLE_TASKLISTS,
-            );
-
-            pulldown_cmark::html::push_html(&mut html, parser);
-
-            html
-        };
+    /// Returns a channel of events that happen in the connected browsers.
+    ///
+    /// Events are only queued once this method has been called, and stop being queued when the
+    /// returned receiver is dropped. Each call replaces the previous channel, so only the most
+    /// recently returned receiver receives new events.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use aurelius::{ClientEvent, Server};
+    ///
+    /// let server = Server::bind("localhost:0")?;
+    ///
+    /// for event in server.events() {
+    ///     if let ClientEvent::JumpToSource { line } = event {
+    ///         println!("jump to line {}", line);
+    ///     }
+    /// }
+    /// # Ok::<_, Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn events(&self) -> Receiver<ClientEvent> {
+        self.shared.events.subscribe()
+    }
 
-        *self.html.write().unwrap() = Some(html);
+    /// Returns the outline of the most recently rendered markdown.
+    ///
+    /// The outline is the tree of the document's headings, with their levels, text, slugs and
+    /// source lines. It is also sent to the browser, where it is shown as a sidebar. Custom
+    /// renderers only produce an outline if they implement [`Renderer::outline`].
+    pub fn outline(&self) -> Vec<Heading> {
+        self.shared.outline()
+    }
 
-        for client in self.md_clients.lock().unwrap().values() {
-            client.send(Signal::NewMarkdown).unwrap();
-        }
+    /// Returns the metadata from the front matter of the most recently rendered markdown.
+    ///
+    /// Front matter is a block of YAML delimited by `---` lines, or of TOML delimited by `+++`
+    /// lines, at the very start of the document. It is removed before the markdown is rendered,
+    /// and its keys and values are returned here as JSON. If the front matter has a `title`, it
+    /// is used as the title of the preview page. A block that isn't a table of keys and values
+    /// is rendered as markdown instead.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use aurelius::Server;
+    ///
+    /// let server = Server::bind("localhost:0")?;
+    /// server.send(String::from("---\ntitle: Notes\n---\n# Monday\n"))?;
+    ///
+    /// // Once the markdown has been rendered:
+    /// assert_eq!(server.metadata()["title"], "Notes");
+    /// # Ok::<_, Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn metadata(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.shared.metadata()
+    }
 
-        Ok(())
+    /// Returns counters describing the work done by the renderer.
+    ///
+    /// This can be used to confirm that publishing unchanged markdown doesn't cause unnecessary
+    /// renders or updates.
+    pub fn render_stats(&self) -> RenderStats {
+        *self.shared.stats.lock().unwrap()
+    }
+
+    /// Returns the number of websocket clients that are currently connected.
+    pub fn client_count(&self) -> usize {
+        self.shared.client_count()
+    }
+
+    /// Set how long the server waits for more markdown before rendering.
+    ///
+    /// When markdown is published with [`send`](Server::send), the server waits until no new
+    /// markdown has been published for this interval before rendering, and only renders the
+    /// latest markdown. This avoids rendering intermediate documents while the user is typing.
+    ///
+    /// Defaults to zero, which renders as soon as the previous render completes.
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.shared.set_debounce(debounce);
+    }
+
+    /// Set the markdown extensions used by the built-in renderer.
+    ///
+    /// If markdown has already been sent to the server, it will be re-rendered with the new
+    /// options and sent to all connected websocket clients.
+    ///
+    /// Defaults to [`RenderOptions::github`] with [`sourcepos`](RenderOptions::sourcepos)
+    /// enabled, which the preview page needs to scroll to a line, to jump to the source of an
+    /// element and to make task list checkboxes clickable. These options have no effect if a
+    /// custom renderer is set.
+    pub fn set_render_options(&mut self, options: RenderOptions) {
+        self.shared
+            .set_render_options(options)
+            .expect("render worker exited");
+    }
+
+    /// Render fenced code blocks in a language by piping them through a command.
+    ///
+    /// This is useful for diagrams: the command receives the content of each block on stdin, and
+    /// its output, such as an SVG image, replaces the block in the preview. Output is cached, so
+    /// the command only runs when a block changes. See [`MarkdownRenderer::add_fence_command`].
+    ///
+    /// Fence commands have no effect if a custom renderer is set.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::process::Command;
+    /// use aurelius::Server;
+    ///
+    /// let mut server = Server::bind("localhost:0")?;
+    ///
+    /// let mut dot = Command::new("dot");
+    /// dot.arg("-Tsvg");
+    /// server.add_fence_command("dot", dot);
+    ///
+    /// server.add_fence_command("svgbob", Command::new("svgbob"));
+    /// # Ok::<_, Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn add_fence_command(&mut self, language: impl Into<String>, command: Command) {
+        self.shared
+            .add_fence_command(language.into(), command)
+            .expect("render worker exited");
+    }
+
+    /// Render fenced code blocks with an in-process handler.
+    ///
+    /// Unlike a fence command, a handler doesn't start a process, so it suits fences such as
+    /// charts or schemas that can be rendered by Rust code. Handlers are consulted in the order
+    /// they were added, before any fence commands. See [`CodeBlockHandler`].
+    ///
+    /// Code block handlers have no effect if a custom renderer is set.
+    pub fn add_code_block_handler(&mut self, handler: Box<dyn CodeBlockHandler>) {
+        self.shared
+            .add_code_block_handler(handler)
+            .expect("render worker exited");
+    }
+
+    /// Add a hook that maps or filters the markdown events before they are written as HTML.
+    ///
+    /// Transforms are the lightest way to customize the built-in renderer: they can rewrite link
+    /// targets, add CSS classes, drop raw HTML, or implement small syntax extensions. See
+    /// [`MarkdownRenderer::add_transform`].
+    ///
+    /// Transforms have no effect if a custom renderer is set.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use aurelius::pulldown_cmark::Event;
+    /// use aurelius::Server;
+    ///
+    /// let mut server = Server::bind("localhost:0")?;
+    ///
+    /// // Never render raw HTML.
+    /// server.add_transform(|event| match event {
+    ///     Event::Html(_) => None,
+    ///     event => Some(event),
+    /// });
+    /// # Ok::<_, Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn add_transform<F>(&mut self, transform: F)
+    where
+        F: for<'a> FnMut(Event<'a>) -> Option<Event<'a>> + Send + 'static,
+    {
+        self.shared
+            .add_transform(transform)
+            .expect("render worker exited");
     }
 
     /// Set the directory that static files will be served from.
@@ -225,14 +473,35 @@ impl Server {
     ///
     /// By default, the server will not serve static files.
     pub fn set_static_root(&mut self, root: impl Into<PathBuf>) {
-        self.config.lock().unwrap().static_root = Some(root.into());
+        self.shared.set_static_root(root.into());
     }
 
     /// Set the highlight.js theme used for code blocks.
     ///
+    /// If server-side highlighting is enabled, the closest equivalent `syntect` theme is used
+    /// instead. See `MarkdownRenderer::set_syntax_highlighting` for details.
+    ///
     /// Defaults to "github".
     pub fn set_highlight_theme(&mut self, theme: String) {
-        self.config.lock().unwrap().highlight_theme = theme;
+        self.shared
+            .set_highlight_theme(theme)
+            .expect("render worker exited");
+    }
+
+    /// Set whether code blocks are highlighted by the server instead of the browser.
+    ///
+    /// By default, code blocks are highlighted in the browser by highlight.js after every update.
+    /// With server-side highlighting, the built-in renderer emits pre-highlighted code blocks with
+    /// inline styles instead, and the page does not load highlight.js at all. This is faster for
+    /// large documents, and the highlighting is preserved if the HTML is exported.
+    ///
+    /// Server-side highlighting has no effect if a custom renderer is set. Requires the
+    /// `server-highlighting` feature.
+    #[cfg(feature = "server-highlighting")]
+    pub fn set_server_side_highlighting(&mut self, enabled: bool) {
+        self.shared
+            .set_server_side_highlighting(enabled)
+            .expect("render worker exited");
     }
 
     /// Set custom CSS links and files to be served with the rendered HTML.
@@ -240,26 +509,7 @@ impl Server {
     /// Accepts URLs and absolute paths. URLs will be inserted as `<link>` tags. The contents of
     /// the paths will be read from disk and served in `<style>` tags.
     pub fn set_custom_css(&mut self, stylesheets: Vec<String>) -> io::Result<()> {
-        let mut files = vec![];
-        let mut links = vec![];
-
-        for stylesheet in &stylesheets {
-            // NB: Absolute paths on Windows will parse as URLs.
-            match Url::parse(&stylesheet) {
-                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => links.push(url),
-                _ => files.push(Path::new(stylesheet.trim_start_matches("file://"))),
-            }
-        }
-
-        let mut config = self.config.lock().unwrap();
-
-        config.custom_styles = files
-            .into_iter()
-            .map(fs::read_to_string)
-            .collect::<Result<Vec<_>, _>>()?;
-        config.css_links = links;
-
-        Ok(())
+        self.shared.set_custom_css(stylesheets)
     }
 
     /// Set an external program to use for rendering the markdown.
@@ -269,7 +519,9 @@ impl Server {
     /// for most use-cases. However, other markdown renderers may provide additional features.
     ///
     /// The `Command` supplied to this function should expect markdown on stdin and print HTML on
-    /// stdout.
+    /// stdout. See [`ExternalRenderer`] for more details, and to configure a timeout. The program
+    /// is spawned for every render; to keep a single process running instead, use
+    /// [`PersistentRenderer`].
     ///
     /// # Example
     ///
@@ -293,11 +545,41 @@ impl Server {
     /// [`pulldown_cmark`]: https://github.com/raphlinus/pulldown-cmark
     /// [CommonMark]: https://commonmark.org/
     /// [`pandoc`]: https://pandoc.org/
-    pub fn set_external_renderer(&mut self, mut command: Command) {
-        command.stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null());
-        self.external_renderer = Some(command);
+    pub fn set_external_renderer(&mut self, command: Command) {
+        self.set_renderer(Box::new(ExternalRenderer::new(command)));
+    }
+
+    /// Set a custom renderer to use for rendering the markdown.
+    ///
+    /// The renderer replaces the built-in [`MarkdownRenderer`]. If markdown has already been sent
+    /// to the server, it will be re-rendered with the new renderer.
+    ///
+    /// The preview page typesets math that the renderer marks up as `<span class="math">`
+    /// elements, and also finds math written with `$...$`, `$$...$$`, `\(...\)` and `\[...\]`
+    /// delimiters in the rendered text.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use aurelius::{RenderError, Renderer, Server};
+    ///
+    /// #[derive(Debug)]
+    /// struct PlainText;
+    ///
+    /// impl Renderer for PlainText {
+    ///     fn render(&mut self, markdown: &str) -> Result<String, RenderError> {
+    ///         Ok(format!("<pre>{}</pre>", markdown.replace('<', "&lt;")))
+    ///     }
+    /// }
+    ///
+    /// let mut server = Server::bind("localhost:0")?;
+    /// server.set_renderer(Box::new(PlainText));
+    /// # Ok::<_, Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn set_renderer(&mut self, renderer: Box<dyn Renderer>) {
+        self.shared
+            .set_renderer(renderer)
+            .expect("render worker exited");
     }
 
     /// Opens the user's default browser with the server's URL in the background.
@@ -327,7 +609,7 @@ impl Server {
     /// Opens a browser with a specified command. The HTTP address of the server will be appended
     /// to the command as an argument.
     pub fn open_specific_browser(&self, mut command: Command) -> io::Result<()> {
-        command.arg(&format!("http://{}", self.addr()));
+        command.arg(format!("http://{}", self.addr()));
 
         command.stdout(Stdio::null()).stderr(Stdio::null());
 
@@ -342,12 +624,17 @@ impl Drop for Server {
         self.shutdown.store(true, Ordering::SeqCst);
         let _ = TcpStream::connect(self.addr());
 
+        // Stop rendering.
+        let _ = self.shared.jobs.send(Job::Stop);
+        self.worker_join_handle.take().unwrap().join().unwrap();
+
         // Shutdown all websocket connections.
         {
-            let clients = std::mem::take(&mut *self.md_clients.lock().unwrap());
+            let clients = std::mem::take(&mut *self.shared.md_clients.lock().unwrap());
 
             for client in clients.values() {
-                client.send(Signal::Close).unwrap();
+                // The client may already be disconnecting.
+                let _ = client.send(Signal::Close);
             }
         }
 
@@ -356,8 +643,147 @@ impl Drop for Server {
     }
 }
 
+/// State shared between a [`Server`] and its [`ServerHandle`]s.
+#[derive(Debug)]
+struct Shared {
+    config: Arc<Mutex<Config>>,
+    jobs: Sender<Job>,
+    md_clients: Arc<Mutex<IdMap<Sender<Signal>>>>,
+    outline: Arc<RwLock<Arc<Vec<Heading>>>>,
+    metadata: Arc<RwLock<Arc<Metadata>>>,
+    events: Events,
+    stats: Arc<Mutex<RenderStats>>,
+}
+
+impl Shared {
+    fn send(&self, markdown: String) -> io::Result<()> {
+        self.submit(Job::Render(markdown))
+    }
+
+    fn client_count(&self) -> usize {
+        self.md_clients.lock().unwrap().len()
+    }
+
+    fn outline(&self) -> Vec<Heading> {
+        self.outline.read().unwrap().to_vec()
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata::clone(&self.metadata.read().unwrap())
+    }
+
+    fn scroll_to_line(&self, line: usize) {
+        for client in self.md_clients.lock().unwrap().values() {
+            // The client may be disconnecting, in which case there's nothing to scroll.
+            let _ = client.send(Signal::Scroll(line));
+        }
+    }
+
+    fn set_debounce(&self, debounce: Duration) {
+        self.config.lock().unwrap().debounce = debounce;
+    }
+
+    fn set_render_options(&self, options: RenderOptions) -> io::Result<()> {
+        self.configure(move |renderers| renderers.markdown.set_options(options))
+    }
+
+    fn add_fence_command(&self, language: String, command: Command) -> io::Result<()> {
+        self.configure(move |renderers| renderers.markdown.add_fence_command(language, command))
+    }
+
+    fn add_code_block_handler(&self, handler: Box<dyn CodeBlockHandler>) -> io::Result<()> {
+        self.configure(move |renderers| renderers.markdown.add_code_block_handler(handler))
+    }
+
+    fn add_transform<F>(&self, transform: F) -> io::Result<()>
+    where
+        F: for<'a> FnMut(Event<'a>) -> Option<Event<'a>> + Send + 'static,
+    {
+        self.configure(move |renderers| renderers.markdown.add_transform(transform))
+    }
+
+    fn set_static_root(&self, root: PathBuf) {
+        self.config.lock().unwrap().static_root = Some(root);
+    }
+
+    fn set_highlight_theme(&self, theme: String) -> io::Result<()> {
+        let mut config = self.config.lock().unwrap();
+        config.highlight_theme = theme;
+
+        #[cfg(feature = "server-highlighting")]
+        {
+            if config.server_side_highlighting {
+                self.update_highlighting(&config)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server-highlighting")]
+    fn set_server_side_highlighting(&self, enabled: bool) -> io::Result<()> {
+        let mut config = self.config.lock().unwrap();
+        config.server_side_highlighting = enabled;
+        self.update_highlighting(&config)
+    }
+
+    #[cfg(feature = "server-highlighting")]
+    fn update_highlighting(&self, config: &Config) -> io::Result<()> {
+        let theme = if config.server_side_highlighting {
+            Some(config.highlight_theme.clone())
+        } else {
+            None
+        };
+
+        self.configure(move |renderers| {
+            renderers
+                .markdown
+                .set_syntax_highlighting(theme.as_deref())
+        })
+    }
+
+    fn set_custom_css(&self, stylesheets: Vec<String>) -> io::Result<()> {
+        let mut files = vec![];
+        let mut links = vec![];
+
+        for stylesheet in &stylesheets {
+            // NB: Absolute paths on Windows will parse as URLs.
+            match Url::parse(stylesheet) {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => links.push(url),
+                _ => files.push(Path::new(stylesheet.trim_start_matches("file://"))),
+            }
+        }
+
+        let mut config = self.config.lock().unwrap();
+
+        config.custom_styles = files
+            .into_iter()
+            .map(fs::read_to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        config.css_links = links;
+
+        Ok(())
+    }
+
+    fn set_renderer(&self, renderer: Box<dyn Renderer>) -> io::Result<()> {
+        self.configure(move |renderers| renderers.custom = Some(renderer))
+    }
+
+    /// Changes the renderers on the render worker, then re-renders the last markdown.
+    fn configure(&self, configure: impl FnOnce(&mut Renderers) + Send + 'static) -> io::Result<()> {
+        self.submit(Job::Configure(Box::new(configure)))
+    }
+
+    /// Sends a job to the render worker, failing if the server is shutting down.
+    fn submit(&self, job: Job) -> io::Result<()> {
+        self.jobs.send(job).map_err(|_| handle::closed())
+    }
+}
+
 enum Signal {
     NewMarkdown,
+    Scroll(usize),
+    Error(String),
     Close,
 }
 
@@ -365,8 +791,10 @@ enum Signal {
 struct Config {
     static_root: Option<PathBuf>,
     highlight_theme: String,
+    server_side_highlighting: bool,
     css_links: Vec<Url>,
     custom_styles: Vec<String>,
+    debounce: Duration,
 }
 
 impl Default for Config {
@@ -374,8 +802,10 @@ impl Default for Config {
         Config {
             static_root: None,
             highlight_theme: String::from("github"),
+            server_side_highlighting: false,
             css_links: vec![],
             custom_styles: vec![],
+            debounce: Duration::from_secs(0),
         }
     }
 }
@@ -385,7 +815,11 @@ struct Handler {
     conn: TcpStream,
     config: Arc<Mutex<Config>>,
     md_clients: Arc<Mutex<IdMap<Sender<Signal>>>>,
-    html: Arc<RwLock<Option<String>>>,
+    html: Arc<RwLock<Option<Arc<Vec<String>>>>>,
+    outline: Arc<RwLock<Arc<Vec<Heading>>>>,
+    metadata: Arc<RwLock<Arc<Metadata>>>,
+    math_delimiters: Arc<AtomicBool>,
+    events: Events,
 }
 
 impl Handler {
@@ -451,6 +885,20 @@ impl Handler {
             }
         };
 
+        // Clients that speak the structured protocol receive JSON messages. Other clients only
+        // receive bare HTML.
+        let subprotocol = req
+            .headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("Sec-WebSocket-Protocol"))
+            .find_map(|header| protocol::negotiate_subprotocol(header.value));
+        let structured = subprotocol.is_some();
+        let format = match subprotocol {
+            Some(protocol::SUBPROTOCOL) => HtmlFormat::Patch,
+            Some(_) => HtmlFormat::Message,
+            None => HtmlFormat::Text,
+        };
+
         write!(self.conn, "HTTP/1.1 101 Switching Protocols\r\n")?;
         write!(self.conn, "Upgrade: websocket\r\n")?;
         write!(self.conn, "Connection: upgrade\r\n")?;
@@ -459,6 +907,9 @@ impl Handler {
             "Sec-WebSocket-Accept: {}\r\n",
             websocket_accept(key)
         )?;
+        if let Some(subprotocol) = subprotocol {
+            write!(self.conn, "Sec-WebSocket-Protocol: {}\r\n", subprotocol)?;
+        }
         write!(self.conn, "\r\n")?;
         self.conn.flush()?;
 
@@ -469,41 +920,107 @@ impl Handler {
         let mut writer = WebSocket::from_raw_socket(self.conn.try_clone()?, Role::Server, None);
         let mut reader = WebSocket::from_raw_socket(self.conn, Role::Server, None);
 
+        // The blocks, outline and metadata that the client is displaying, and whether it looks
+        // for math delimiters in them.
+        let mut displayed = Arc::new(vec![]);
+        let mut displayed_outline = Arc::new(vec![]);
+        let mut displayed_metadata = Arc::new(Metadata::new());
+        let mut displayed_math_delimiters = false;
+
+        // The client must know how to find math before it displays the HTML.
+        if structured {
+            if let Some(message) = update_math(&self.math_delimiters, &mut displayed_math_delimiters)
+            {
+                writer.write_message(message)?;
+            }
+        }
+
         // If there's HTML already present, send it to the client.
-        {
-            let html = self.html.read().unwrap();
-            if let Some(html) = html.as_ref() {
-                writer.write_message(Message::text(html))?;
+        if let Some(message) = update(&self.html, &mut displayed, format) {
+            writer.write_message(message)?;
+        }
+
+        if structured {
+            let outline = update_outline(&self.outline, &mut displayed_outline);
+            let metadata = update_metadata(&self.metadata, &mut displayed_metadata);
+
+            for message in outline.into_iter().chain(metadata) {
+                writer.write_message(message)?;
             }
         }
 
+        self.events.emit(ClientEvent::Connected);
+
         let clients = Arc::clone(&self.md_clients);
-        thread::spawn(move || loop {
-            match reader.read_message() {
-                Err(_) => break,
-                Ok(Message::Close(_)) => {
-                    // The client may already be dropped by the time we get here.
-                    clients.lock().unwrap().remove(client_id);
-                    break;
+        let events = self.events.clone();
+        thread::spawn(move || {
+            loop {
+                match reader.read_message() {
+                    Err(_) | Ok(Message::Close(_)) => break,
+                    Ok(Message::Text(text)) if structured => {
+                        match serde_json::from_str::<ClientMessage>(&text) {
+                            Ok(message) => events.emit(message.into()),
+                            Err(e) => warn!("ignoring invalid message from client: {}", e),
+                        }
+                    }
+                    Ok(_) => (),
                 }
-                Ok(_) => (),
             }
+
+            // The client may already be dropped by the time we get here.
+            clients.lock().unwrap().remove(client_id);
+            events.emit(ClientEvent::Disconnected);
         });
 
         loop {
             select! {
                 recv(md_rx) -> msg => {
-                    // The server is being dropped.
-                    if let Ok(Signal::Close) | Err(_) = msg {
-                        // Ignore errors, since the socket may already be closed.
-                        let _ = writer.close(None);
-                        let _ = writer.write_pending();
-                        break;
-                    }
+                    let messages: Vec<_> = match msg {
+                        Ok(Signal::NewMarkdown) => {
+                            let mut messages = vec![];
+
+                            if structured {
+                                messages.extend(update_math(
+                                    &self.math_delimiters,
+                                    &mut displayed_math_delimiters,
+                                ));
+                            }
+
+                            messages.extend(update(&self.html, &mut displayed, format));
+
+                            // Other clients only understand HTML.
+                            if structured {
+                                messages
+                                    .extend(update_outline(&self.outline, &mut displayed_outline));
+                                messages.extend(update_metadata(
+                                    &self.metadata,
+                                    &mut displayed_metadata,
+                                ));
+                            }
+
+                            messages
+                        }
+                        Ok(Signal::Scroll(line)) if structured => {
+                            vec![Message::text(ServerMessage::Scroll { line }.to_json())]
+                        }
+                        Ok(Signal::Error(message)) if structured => {
+                            vec![Message::text(ServerMessage::Error { message }.to_json())]
+                        }
+                        // Other clients only understand HTML.
+                        Ok(Signal::Scroll(_)) | Ok(Signal::Error(_)) => vec![],
+                        // The server is being dropped.
+                        Ok(Signal::Close) | Err(_) => {
+                            // Ignore errors, since the socket may already be closed.
+                            let _ = writer.close(None);
+                            let _ = writer.write_pending();
+                            break;
+                        }
+                    };
 
-                    let html = self.html.read().unwrap();
-                    writer.write_message(Message::text(html.as_ref().expect("no HTML present")))?;
-                    writer.write_pending()?;
+                    for message in messages {
+                        writer.write_message(message)?;
+                        writer.write_pending()?;
+                    }
                 }
             }
         }
@@ -517,7 +1034,7 @@ impl Handler {
         if path.starts_with("/__/") {
             let path = path.trim_start_matches("/__/");
 
-            match STATIC_FILES.get_file(path) {
+            match static_file(path) {
                 Some(file) => self.write_file_contents(file.path, file.contents)?,
                 None => write!(self.conn, "HTTP/1.1 404 Not Found\r\n\r\n")?,
             }
@@ -527,14 +1044,21 @@ impl Handler {
                 remote_custom_css: &'a [Url],
                 local_custom_css: &'a [String],
                 highlight_theme: &'a str,
+                server_side_highlighting: bool,
+                bundled_katex: bool,
+                metadata: &'a Metadata,
             }
 
             let html = {
                 let config = self.config.lock().unwrap();
+                let metadata = self.metadata.read().unwrap();
                 let data = Data {
                     remote_custom_css: &config.css_links,
                     local_custom_css: &config.custom_styles,
                     highlight_theme: &config.highlight_theme,
+                    server_side_highlighting: config.server_side_highlighting,
+                    bundled_katex: cfg!(feature = "bundled-katex"),
+                    metadata: &metadata,
                 };
                 Handlebars::new()
                     .render_template(include_str!("../templates/markdown_view.html"), &data)
@@ -554,7 +1078,7 @@ impl Handler {
                 .deref()
                 .static_root
                 .clone()
-                .map(|root| root.join(&url_path_to_file_path(path)));
+                .map(|root| root.join(url_path_to_file_path(path)));
 
             match root {
                 Some(file_path) => self.write_file(&file_path)?,
@@ -582,7 +1106,7 @@ impl Handler {
     }
 
     fn write_file(&mut self, path: &Path) -> io::Result<()> {
-        if let Ok(contents) = fs::read(&path) {
+        if let Ok(contents) = fs::read(path) {
             self.write_file_contents(path, &contents)?;
         } else {
             write!(self.conn, "HTTP/1.1 404 Not Found\r\n\r\n")?;
@@ -592,32 +1116,141 @@ impl Handler {
     }
 }
 
-fn websocket_accept(key: &[u8]) -> String {
-    static GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Looks up a file that is bundled with the server, relative to `/__/`.
+fn static_file(path: &str) -> Option<include_dir::File<'static>> {
+    // Without the feature, the page loads KaTeX from the CDN instead.
+    if cfg!(not(feature = "bundled-katex")) && path.starts_with("vendor/katex/") {
+        return None;
+    }
 
-    let mut accept = Sha1::new();
-    accept.input(key);
-    accept.input(GUID);
+    STATIC_FILES.get_file(path)
+}
 
-    base64::encode(&accept.result())
+/// How a client receives rendered HTML.
+#[derive(Debug, Clone, Copy)]
+enum HtmlFormat {
+    /// A patch against the blocks that the client is displaying.
+    Patch,
+
+    /// The whole document, in an HTML message.
+    Message,
+
+    /// The whole document, as bare text.
+    Text,
 }
 
-fn url_path_to_file_path(path: &str) -> PathBuf {
-    path.trim_start_matches('/').split('/').collect()
+/// Returns the message that brings a client up to date with the latest render, if any.
+fn update(
+    html: &RwLock<Option<Arc<Vec<String>>>>,
+    displayed: &mut Arc<Vec<String>>,
+    format: HtmlFormat,
+) -> Option<Message> {
+    let blocks = html.read().unwrap().clone()?;
+
+    let message = match format {
+        HtmlFormat::Patch => {
+            protocol::patch(displayed, &blocks).map(|patch| Message::text(patch.to_json()))
+        }
+        HtmlFormat::Message => {
+            let message = ServerMessage::Html {
+                html: blocks.concat(),
+            };
+            Some(Message::text(message.to_json()))
+        }
+        HtmlFormat::Text => Some(Message::text(blocks.concat())),
+    };
+
+    *displayed = blocks;
+    message
 }
 
-#[cfg(test)]
-mod tests {
+/// Returns the message that brings a structured client's outline up to date, if it changed.
+fn update_outline(
+    outline: &RwLock<Arc<Vec<Heading>>>,
+    displayed: &mut Arc<Vec<Heading>>,
+) -> Option<Message> {
+    let outline = outline.read().unwrap().clone();
+
+    if outline == *displayed {
+        return None;
+    }
+
+    let message = ServerMessage::Outline {
+        headings: outline.to_vec(),
+    };
+
+    *displayed = outline;
+    Some(Message::text(message.to_json()))
+}
+
+/// Returns the message that brings a structured client's metadata up to date, if it changed.
+fn update_metadata(
+    metadata: &RwLock<Arc<Metadata>>,
+    displayed: &mut Arc<Metadata>,
+) -> Option<Message> {
+    let metadata = metadata.read().unwrap().clone();
+
+    if metadata == *displayed {
+        return None;
+    }
+
+    let message = ServerMessage::Metadata {
+        metadata: Metadata::clone(&metadata),
+    };
+
+    *displayed = metadata;
+    Some(Message::text(message.to_json()))
+}
+
+/// Returns the message that tells a structured client whether to look for math delimiters, if it
+/// changed. Clients don't look for delimiters until they are told to.
+fn update_math(math_delimiters: &AtomicBool, displayed: &mut bool) -> Option<Message> {
+    let delimiters = math_delimiters.load(Ordering::SeqCst);
+
+    if delimiters == *displayed {
+        return None;
+    }
+
+    *displayed = delimiters;
+    Some(Message::text(ServerMessage::Math { delimiters }.to_json()))
+}
+
+fn websocket_accept(key: &[u8]) -> String {
+    static GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+    let mut accept = Sha1::new();
+    accept.input(key);
+    accept.input(GUID);
+
+    base64::encode(&accept.result())
+}
+
+fn url_path_to_file_path(path: &str) -> PathBuf {
+    path.trim_start_matches('/').split('/').collect()
+}
+
+#[cfg(test)]
+mod tests {
     use std::error::Error;
-    use std::io::{Read, Write};
+    use std::io::{self, Read, Write};
     use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     use matches::assert_matches;
     use tungstenite::handshake::client::Request;
     use tungstenite::Message;
     use tungstenite::WebSocket;
 
-    use super::Server;
+    use super::protocol::{ServerMessage, SUBPROTOCOL, SUBPROTOCOL_V1};
+    use super::{
+        set_task_checked, ClientEvent, Heading, RenderError, RenderOptions, RenderStats, Renderer,
+        Server,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
 
     fn assert_websocket_closed<S: Read + Write>(websocket: &mut WebSocket<S>) {
         loop {
@@ -637,7 +1270,7 @@ mod tests {
         );
         assert_eq!(
             super::url_path_to_file_path("/a/b/c/d"),
-            vec!["a", "b", "c", "d"].iter().collect::<PathBuf>(),
+            ["a", "b", "c", "d"].iter().collect::<PathBuf>(),
         );
     }
 
@@ -668,16 +1301,16 @@ mod tests {
 
     #[test]
     fn send_with_no_clients() -> Result<(), Box<dyn Error>> {
-        let mut server = Server::bind("localhost:0")?;
+        let server = Server::bind("localhost:0")?;
 
-        server.send(String::from("This shouldn't hang")).unwrap();
+        server.send(String::from("This shouldn't hang"))?;
 
         Ok(())
     }
 
     #[test]
     fn send_html() -> Result<(), Box<dyn Error>> {
-        let mut server = Server::bind("localhost:0")?;
+        let server = Server::bind("localhost:0")?;
         let addr = server.addr();
 
         let req = Request {
@@ -700,7 +1333,98 @@ mod tests {
 
     #[test]
     fn send_markdown() -> Result<(), Box<dyn Error>> {
+        let server = Server::bind("localhost:0")?;
+        let addr = server.addr();
+
+        let req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+
+        let (mut websocket, _) = tungstenite::connect(req)?;
+
+        server.send(String::from("*Hello*"))?;
+        let message = websocket.read_message()?;
+        assert_eq!(message.to_text()?.trim(), "<p data-sourcepos=\"1:1-1:7\"><em>Hello</em></p>");
+
+        Ok(())
+    }
+
+    #[test]
+    fn render_options_rerender() -> Result<(), Box<dyn Error>> {
+        let mut server = Server::bind("localhost:0")?;
+        let addr = server.addr();
+
+        let req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+
+        let (mut websocket, _) = tungstenite::connect(req)?;
+
+        server.send(String::from("~~Hello~~"))?;
+        let message = websocket.read_message()?;
+        assert_eq!(message.to_text()?.trim(), "<p data-sourcepos=\"1:1-1:9\"><del>Hello</del></p>");
+
+        server.set_render_options(RenderOptions::commonmark());
+        let message = websocket.read_message()?;
+        assert_eq!(message.to_text()?.trim(), "<p>~~Hello~~</p>");
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_stale_markdown() -> Result<(), Box<dyn Error>> {
+        #[derive(Debug)]
+        struct SlowRenderer(Arc<AtomicUsize>);
+
+        impl Renderer for SlowRenderer {
+            fn render(&mut self, markdown: &str) -> Result<String, RenderError> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                Ok(markdown.to_owned())
+            }
+        }
+
+        let renders = Arc::new(AtomicUsize::new(0));
+
+        let mut server = Server::bind("localhost:0")?;
+        server.set_renderer(Box::new(SlowRenderer(Arc::clone(&renders))));
+        server.set_debounce(Duration::from_millis(20));
+        let addr = server.addr();
+
+        let req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+
+        let (mut websocket, _) = tungstenite::connect(req)?;
+
+        for i in 0..10 {
+            server.send(i.to_string())?;
+        }
+
+        let message = websocket.read_message()?;
+        assert_eq!(message.to_text()?, "9");
+        assert_eq!(renders.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn send_renders_while_typing() -> Result<(), Box<dyn Error>> {
+        #[derive(Debug)]
+        struct SlowRenderer;
+
+        impl Renderer for SlowRenderer {
+            fn render(&mut self, markdown: &str) -> Result<String, RenderError> {
+                thread::sleep(Duration::from_millis(50));
+                Ok(markdown.to_owned())
+            }
+        }
+
         let mut server = Server::bind("localhost:0")?;
+        server.set_renderer(Box::new(SlowRenderer));
         let addr = server.addr();
 
         let req = Request {
@@ -710,9 +1434,522 @@ mod tests {
 
         let (mut websocket, _) = tungstenite::connect(req)?;
 
+        while server.client_count() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        // Documents arrive faster than they can be rendered, but the preview still updates.
+        for i in 0..10 {
+            server.send(i.to_string())?;
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let message = websocket.read_message()?;
+        assert_ne!(message.to_text()?, "9");
+
+        let mut last = message.into_text()?;
+        while last != "9" {
+            last = websocket.read_message()?.into_text()?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_unchanged_renders() -> Result<(), Box<dyn Error>> {
+        let server = Server::bind("localhost:0")?;
+        let addr = server.addr();
+
+        let req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+
+        let (mut websocket, _) = tungstenite::connect(req)?;
+
+        // Nothing is sent to clients for unchanged documents, so wait for the counters instead.
+        let wait_for_stats = |server: &Server, expected: RenderStats| {
+            let start = Instant::now();
+            while server.render_stats() != expected && start.elapsed() < TIMEOUT {
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(server.render_stats(), expected);
+        };
+
+        server.send(String::from("*Hello*"))?;
+        let message = websocket.read_message()?;
+        assert_eq!(message.to_text()?.trim(), "<p data-sourcepos=\"1:1-1:7\"><em>Hello</em></p>");
+
         server.send(String::from("*Hello*"))?;
+        wait_for_stats(
+            &server,
+            RenderStats {
+                renders: 1,
+                unchanged_markdown: 1,
+                unchanged_html: 0,
+            },
+        );
+
+        server.send(String::from("_Hello_"))?;
+        wait_for_stats(
+            &server,
+            RenderStats {
+                renders: 2,
+                unchanged_markdown: 1,
+                unchanged_html: 1,
+            },
+        );
+
+        server.send(String::from("*World*"))?;
         let message = websocket.read_message()?;
-        assert_eq!(message.to_text()?.trim(), "<p><em>Hello</em></p>");
+        assert_eq!(message.to_text()?.trim(), "<p data-sourcepos=\"1:1-1:7\"><em>World</em></p>");
+        assert_eq!(server.render_stats().renders, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn send_from_handle() -> Result<(), Box<dyn Error>> {
+        let server = Server::bind("localhost:0")?;
+        let addr = server.addr();
+
+        let req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+
+        let (mut websocket, _) = tungstenite::connect(req)?;
+
+        let handle = server.handle();
+        thread::spawn(move || handle.send(String::from("*Hello*")))
+            .join()
+            .unwrap()?;
+
+        let message = websocket.read_message()?;
+        assert_eq!(message.to_text()?.trim(), "<p data-sourcepos=\"1:1-1:7\"><em>Hello</em></p>");
+
+        assert_eq!(server.handle().client_count()?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn scroll_to_line() -> Result<(), Box<dyn Error>> {
+        let server = Server::bind("localhost:0")?;
+        let addr = server.addr();
+
+        let mut req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+        req.add_protocol(SUBPROTOCOL.into());
+
+        let (mut websocket, _) = tungstenite::connect(req)?;
+
+        // Wait for the client to be registered.
+        while server.client_count() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        server.scroll_to_line(12);
+        server.handle().scroll_to_line(3)?;
+
+        let message = websocket.read_message()?;
+        assert_eq!(message.to_text()?, r#"{"type":"scroll","line":12}"#);
+
+        let message = websocket.read_message()?;
+        assert_eq!(message.to_text()?, r#"{"type":"scroll","line":3}"#);
+
+        Ok(())
+    }
+
+    #[test]
+    fn structured_messages() -> Result<(), Box<dyn Error>> {
+        let server = Server::bind("localhost:0")?;
+        let addr = server.addr();
+
+        let mut req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+        req.add_protocol(SUBPROTOCOL.into());
+
+        let (mut websocket, response) = tungstenite::connect(req)?;
+        assert_eq!(
+            response.headers.find_first("Sec-WebSocket-Protocol"),
+            Some(SUBPROTOCOL.as_bytes())
+        );
+
+        server.send(String::from("# Title\n\nOne\n\nTwo\n"))?;
+
+        let message: ServerMessage = serde_json::from_str(websocket.read_message()?.to_text()?)?;
+        assert_eq!(
+            message,
+            ServerMessage::Patch {
+                start: 0,
+                remove: 0,
+                insert: vec![
+                    String::from(
+                        "<h1 data-sourcepos=\"1:1-1:7\" id=\"title\">\
+                         <a class=\"anchor\" aria-hidden=\"true\" href=\"#title\"></a>Title</h1>\n"
+                    ),
+                    String::from("<p data-sourcepos=\"3:1-3:3\">One</p>\n"),
+                    String::from("<p data-sourcepos=\"5:1-5:3\">Two</p>\n"),
+                ],
+            }
+        );
+
+        let heading = Heading {
+            level: 1,
+            text: String::from("Title"),
+            slug: String::from("title"),
+            line: 1,
+            children: vec![],
+        };
+
+        let message: ServerMessage = serde_json::from_str(websocket.read_message()?.to_text()?)?;
+        assert_eq!(
+            message,
+            ServerMessage::Outline {
+                headings: vec![heading.clone()],
+            }
+        );
+        assert_eq!(server.outline(), [heading]);
+
+        // Only the changed block is sent, and the outline is only sent when it changes.
+        server.send(String::from("# Title\n\n*One*\n\nTwo\n"))?;
+
+        let message: ServerMessage = serde_json::from_str(websocket.read_message()?.to_text()?)?;
+        assert_eq!(
+            message,
+            ServerMessage::Patch {
+                start: 1,
+                remove: 1,
+                insert: vec![String::from(
+                    "<p data-sourcepos=\"3:1-3:5\"><em>One</em></p>\n"
+                )],
+            }
+        );
+
+        server.send(String::from("# Title\n"))?;
+
+        let message: ServerMessage = serde_json::from_str(websocket.read_message()?.to_text()?)?;
+        assert_eq!(
+            message,
+            ServerMessage::Patch {
+                start: 1,
+                remove: 2,
+                insert: vec![],
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn front_matter_metadata() -> Result<(), Box<dyn Error>> {
+        let server = Server::bind("localhost:0")?;
+        let addr = server.addr();
+
+        let mut req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+        req.add_protocol(SUBPROTOCOL.into());
+
+        let (mut websocket, _) = tungstenite::connect(req)?;
+
+        server.send(String::from("---\ntitle: Notes & more\ntags: [a]\n---\nMonday\n"))?;
+
+        let message: ServerMessage = serde_json::from_str(websocket.read_message()?.to_text()?)?;
+        assert_eq!(
+            message,
+            ServerMessage::Patch {
+                start: 0,
+                remove: 0,
+                insert: vec![String::from("<p data-sourcepos=\"5:1-5:6\">Monday</p>\n")],
+            }
+        );
+
+        let expected = serde_json::json!({"title": "Notes & more", "tags": ["a"]});
+
+        let message: ServerMessage = serde_json::from_str(websocket.read_message()?.to_text()?)?;
+        assert_matches!(
+            message,
+            ServerMessage::Metadata { metadata } if metadata == *expected.as_object().unwrap()
+        );
+        assert_eq!(serde_json::Value::from(server.metadata()), expected);
+
+        let text = reqwest::blocking::get(&format!("http://{}", addr))?.text()?;
+        assert!(text.contains("<title>Notes &amp; more</title>"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn render_errors_sent_to_clients() -> Result<(), Box<dyn Error>> {
+        #[derive(Debug)]
+        struct Broken;
+
+        impl Renderer for Broken {
+            fn render(&mut self, _: &str) -> Result<String, RenderError> {
+                Err(RenderError::Other("oh no".into()))
+            }
+        }
+
+        let mut server = Server::bind("localhost:0")?;
+        server.set_renderer(Box::new(Broken));
+        let addr = server.addr();
+
+        let mut req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+        req.add_protocol(SUBPROTOCOL.into());
+
+        let (mut websocket, _) = tungstenite::connect(req)?;
+
+        while server.client_count() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        server.send(String::from("# Markdown"))?;
+
+        let message: ServerMessage = serde_json::from_str(websocket.read_message()?.to_text()?)?;
+        assert_eq!(
+            message,
+            ServerMessage::Error {
+                message: String::from("oh no")
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn renderer_panics_sent_to_clients() -> Result<(), Box<dyn Error>> {
+        #[derive(Debug)]
+        struct Panicky;
+
+        impl Renderer for Panicky {
+            fn render(&mut self, markdown: &str) -> Result<String, RenderError> {
+                if markdown == "panic" {
+                    panic!("oh no");
+                }
+
+                Ok(markdown.to_owned())
+            }
+        }
+
+        let mut server = Server::bind("localhost:0")?;
+        server.set_renderer(Box::new(Panicky));
+        let addr = server.addr();
+
+        let mut req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+        req.add_protocol(SUBPROTOCOL.into());
+
+        let (mut websocket, _) = tungstenite::connect(req)?;
+
+        while server.client_count() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        server.send(String::from("panic"))?;
+
+        let message: ServerMessage = serde_json::from_str(websocket.read_message()?.to_text()?)?;
+        assert_eq!(
+            message,
+            ServerMessage::Error {
+                message: String::from("renderer panicked: oh no")
+            }
+        );
+
+        // The server keeps rendering after a panic.
+        server.send(String::from("<p>Hello</p>"))?;
+
+        // Custom renderers don't parse math, so the client has to look for delimiters.
+        let message: ServerMessage = serde_json::from_str(websocket.read_message()?.to_text()?)?;
+        assert_eq!(message, ServerMessage::Math { delimiters: true });
+
+        let message: ServerMessage = serde_json::from_str(websocket.read_message()?.to_text()?)?;
+        assert_matches!(message, ServerMessage::Patch { .. });
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_events() -> Result<(), Box<dyn Error>> {
+        let server = Server::bind("localhost:0")?;
+        let events = server.events();
+        let addr = server.addr();
+
+        let mut req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+        req.add_protocol(SUBPROTOCOL.into());
+
+        let (mut websocket, _) = tungstenite::connect(req)?;
+        assert_eq!(events.recv_timeout(TIMEOUT)?, ClientEvent::Connected);
+
+        websocket.write_message(Message::text(r#"{"type":"not_an_event"}"#))?;
+        websocket.write_message(Message::text(
+            r#"{"type":"checkbox_toggled","line":3,"checked":true}"#,
+        ))?;
+        assert_eq!(
+            events.recv_timeout(TIMEOUT)?,
+            ClientEvent::CheckboxToggled {
+                line: 3,
+                checked: true
+            }
+        );
+
+        websocket.close(None)?;
+        assert_eq!(events.recv_timeout(TIMEOUT)?, ClientEvent::Disconnected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn v1_clients_receive_whole_html() -> Result<(), Box<dyn Error>> {
+        let server = Server::bind("localhost:0")?;
+        let addr = server.addr();
+
+        let mut req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+        req.add_protocol(SUBPROTOCOL_V1.into());
+
+        let (mut websocket, response) = tungstenite::connect(req)?;
+        assert_eq!(
+            response.headers.find_first("Sec-WebSocket-Protocol"),
+            Some(SUBPROTOCOL_V1.as_bytes())
+        );
+
+        while server.client_count() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        server.send(String::from("One\n\nTwo\n"))?;
+
+        let message: ServerMessage = serde_json::from_str(websocket.read_message()?.to_text()?)?;
+        assert_eq!(
+            message,
+            ServerMessage::Html {
+                html: String::from(
+                    "<p data-sourcepos=\"1:1-1:3\">One</p>\n<p data-sourcepos=\"3:1-3:3\">Two</p>\n"
+                ),
+            }
+        );
+
+        server.send(String::from("*One*\n\nTwo\n"))?;
+
+        let message: ServerMessage = serde_json::from_str(websocket.read_message()?.to_text()?)?;
+        assert_eq!(
+            message,
+            ServerMessage::Html {
+                html: String::from(
+                    "<p data-sourcepos=\"1:1-1:5\"><em>One</em></p>\n\
+                     <p data-sourcepos=\"3:1-3:3\">Two</p>\n"
+                ),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn toggle_rendered_checkbox() -> Result<(), Box<dyn Error>> {
+        let server = Server::bind("localhost:0")?;
+        let events = server.events();
+        let addr = server.addr();
+
+        let mut req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+        req.add_protocol(SUBPROTOCOL.into());
+
+        let (mut websocket, _) = tungstenite::connect(req)?;
+        assert_eq!(events.recv_timeout(TIMEOUT)?, ClientEvent::Connected);
+
+        let markdown = "# Tasks\n\n- [x] write docs\n- [ ] ship it\n";
+        server.send(String::from(markdown))?;
+
+        let html = match serde_json::from_str(websocket.read_message()?.to_text()?)? {
+            ServerMessage::Patch { insert, .. } => insert.concat(),
+            message => panic!("unexpected message: {:?}", message),
+        };
+
+        // The preview page makes checkboxes clickable inside list items with a source position,
+        // and reports the line that the item starts on.
+        let item = "<li data-sourcepos=\"4:1-4:13\"><input disabled=\"\" type=\"checkbox\"/>";
+        assert!(html.contains(item), "no clickable checkbox in {:?}", html);
+
+        websocket.write_message(Message::text(
+            r#"{"type":"checkbox_toggled","line":4,"checked":true}"#,
+        ))?;
+
+        let (line, checked) = match events.recv_timeout(TIMEOUT)? {
+            ClientEvent::CheckboxToggled { line, checked } => (line, checked),
+            event => panic!("unexpected event: {:?}", event),
+        };
+        assert_eq!(
+            set_task_checked(markdown, line, checked).as_deref(),
+            Some("# Tasks\n\n- [x] write docs\n- [x] ship it\n")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_clients_only_receive_html() -> Result<(), Box<dyn Error>> {
+        let server = Server::bind("localhost:0")?;
+        let addr = server.addr();
+
+        let req = Request {
+            url: format!("ws://{}", addr).parse()?,
+            extra_headers: None,
+        };
+
+        let (mut websocket, _) = tungstenite::connect(req)?;
+
+        while server.client_count() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        server.scroll_to_line(12);
+        server.send(String::from("*Hello*"))?;
+
+        let message = websocket.read_message()?;
+        assert_eq!(message.to_text()?.trim(), "<p data-sourcepos=\"1:1-1:7\"><em>Hello</em></p>");
+
+        Ok(())
+    }
+
+    #[test]
+    fn handle_closed_on_drop() -> Result<(), Box<dyn Error>> {
+        let server = Server::bind("localhost:0")?;
+        let handle = server.handle();
+
+        assert!(!handle.is_closed());
+        handle.send(String::from("# Markdown"))?;
+
+        drop(server);
+
+        assert!(handle.is_closed());
+        assert_eq!(
+            handle.send(String::from("# Markdown")).unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+        assert_eq!(
+            handle.client_count().unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
 
         Ok(())
     }
@@ -738,7 +1975,7 @@ mod tests {
 
     #[test]
     fn queue_html_if_no_clients() -> Result<(), Box<dyn Error>> {
-        let mut server = Server::bind("localhost:0")?;
+        let server = Server::bind("localhost:0")?;
         let addr = server.addr();
 
         server.send(String::from("# Markdown"))?;
@@ -752,7 +1989,11 @@ mod tests {
 
         let message = websocket.read_message().unwrap();
         assert!(message.is_text(), "message was not text: {:?}", message);
-        assert_eq!(message.to_text().unwrap().trim(), "<h1>Markdown</h1>");
+        assert_eq!(
+            message.to_text().unwrap().trim(),
+            "<h1 data-sourcepos=\"1:1-1:10\" id=\"markdown\">\
+             <a class=\"anchor\" aria-hidden=\"true\" href=\"#markdown\"></a>Markdown</h1>"
+        );
         websocket.close(None).unwrap();
 
         assert_websocket_closed(&mut websocket);
@@ -762,7 +2003,7 @@ mod tests {
 
     #[test]
     fn closed_websocket_removed_from_clients() -> Result<(), Box<dyn Error>> {
-        let mut server = Server::bind("localhost:0")?;
+        let server = Server::bind("localhost:0")?;
         let addr = server.addr();
 
         let req = Request {
@@ -777,7 +2018,7 @@ mod tests {
 
         assert_websocket_closed(&mut websocket);
 
-        server.send(String::from("# Markdown")).unwrap();
+        server.send(String::from("# Markdown"))?;
 
         assert_matches!(
             websocket.read_message(),
@@ -0,0 +1,171 @@
+//! The websocket protocol spoken between the server and the preview page.
+//!
+//! Clients that request the [`SUBPROTOCOL`] in the `Sec-WebSocket-Protocol` header of the
+//! websocket handshake receive every message as a JSON-encoded [`ServerMessage`], tagged by its
+//! `type`:
+//!
+//! ```json
+//! {"type":"patch","start":0,"remove":0,"insert":["<h1>Hello</h1>\n","<p>world</p>\n"]}
+//! {"type":"outline","headings":[{"level":1,"text":"Hello","slug":"hello","line":1,"children":[]}]}
+//! {"type":"scroll","line":12}
+//! ```
+//!
+//! Such clients may also send JSON-encoded [`ClientMessage`]s to the server, which are delivered
+//! to the host program as [`ClientEvent`](crate::ClientEvent)s.
+//!
+//! Clients that request [`SUBPROTOCOL_V1`] instead receive the same messages, except that the
+//! HTML is sent as a whole document in a [`ServerMessage::Html`] message rather than as a
+//! [`ServerMessage::Patch`].
+//!
+//! Clients that don't request a subprotocol only receive the rendered HTML, as bare text
+//! messages. New kinds of messages may be added to a protocol version, so clients should ignore
+//! messages with a type that they don't recognize. Incompatible changes will use a new
+//! subprotocol.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::render::Heading;
+
+/// The websocket subprotocol for structured messages.
+pub const SUBPROTOCOL: &str = "aurelius.v2";
+
+/// The previous version of the [`SUBPROTOCOL`], which sends [`ServerMessage::Html`] instead of
+/// [`ServerMessage::Patch`]. The server still accepts it from older clients.
+pub const SUBPROTOCOL_V1: &str = "aurelius.v1";
+
+/// A message sent from the server to clients that speak the [`SUBPROTOCOL`] or [`SUBPROTOCOL_V1`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ServerMessage {
+    /// Newly rendered HTML that should replace the preview. Only sent to clients that speak
+    /// [`SUBPROTOCOL_V1`].
+    Html {
+        /// The HTML fragment.
+        html: String,
+    },
+
+    /// Newly rendered HTML, as a change to the blocks that the client is displaying.
+    ///
+    /// The client should remove `remove` blocks starting at index `start`, then insert the
+    /// `insert` blocks in their place. Each block is an HTML fragment. A newly connected client
+    /// is displaying no blocks, so its first patch contains the whole document. Only sent to
+    /// clients that speak the [`SUBPROTOCOL`].
+    Patch {
+        /// The index of the first block to replace.
+        start: usize,
+
+        /// The number of blocks to remove.
+        remove: usize,
+
+        /// The blocks to insert.
+        insert: Vec<String>,
+    },
+
+    /// The outline of the newly rendered document. Sent when the outline changes.
+    Outline {
+        /// The top-level headings of the document.
+        headings: Vec<Heading>,
+    },
+
+    /// The metadata from the front matter of the newly rendered document. Sent when the metadata
+    /// changes.
+    Metadata {
+        /// The metadata. See [`Server::metadata`](crate::Server::metadata).
+        metadata: Map<String, Value>,
+    },
+
+    /// Whether the client should look for math delimiters in the HTML. Sent before the HTML when
+    /// it changes. Until then, clients should assume that `delimiters` is `false`.
+    Math {
+        /// If `true`, the renderer leaves math as text, such as `$x^2$`, so the client should find
+        /// it by scanning the text for delimiters. If `false`, math is only marked up as
+        /// `<span class="math">` elements, and dollar signs in the text are not math.
+        delimiters: bool,
+    },
+
+    /// Scroll the preview to a line of the markdown.
+    Scroll {
+        /// The line, numbered from 1.
+        line: usize,
+    },
+
+    /// Rendering failed. The last rendered HTML is still current.
+    Error {
+        /// A description of the error.
+        message: String,
+    },
+}
+
+/// A message sent from a client that speaks the [`SUBPROTOCOL`] or [`SUBPROTOCOL_V1`] to the
+/// server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ClientMessage {
+    /// A link was clicked. See [`ClientEvent::LinkClicked`](crate::ClientEvent::LinkClicked).
+    LinkClicked {
+        /// The target of the link.
+        href: String,
+    },
+
+    /// A task list checkbox was clicked. See [`ClientEvent::CheckboxToggled`](crate::ClientEvent::CheckboxToggled).
+    CheckboxToggled {
+        /// The line of the markdown containing the task.
+        line: usize,
+
+        /// Whether the checkbox is now checked.
+        checked: bool,
+    },
+
+    /// The user asked to jump to a line of the markdown. See [`ClientEvent::JumpToSource`](crate::ClientEvent::JumpToSource).
+    JumpToSource {
+        /// The line of the markdown.
+        line: usize,
+    },
+}
+
+impl ServerMessage {
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("could not serialize message")
+    }
+}
+
+/// Computes the patch that turns the `old` blocks into the `new` blocks, or `None` if they are
+/// the same.
+///
+/// Edits usually touch a single region of a document, so the patch replaces everything between
+/// the longest common prefix and suffix.
+pub(crate) fn patch(old: &[String], new: &[String]) -> Option<ServerMessage> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+
+    if prefix == old.len() && prefix == new.len() {
+        return None;
+    }
+
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    Some(ServerMessage::Patch {
+        start: prefix,
+        remove: old.len() - prefix - suffix,
+        insert: new[prefix..new.len() - suffix].to_vec(),
+    })
+}
+
+/// Returns the subprotocol to speak with a client, given the value of its
+/// `Sec-WebSocket-Protocol` header. The [`SUBPROTOCOL`] is preferred over [`SUBPROTOCOL_V1`].
+pub(crate) fn negotiate_subprotocol(header: &[u8]) -> Option<&'static str> {
+    let header = String::from_utf8_lossy(header);
+    let requested: Vec<_> = header.split(',').map(str::trim).collect();
+
+    [SUBPROTOCOL, SUBPROTOCOL_V1]
+        .iter()
+        .copied()
+        .find(|protocol| requested.contains(protocol))
+}
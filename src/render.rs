@@ -0,0 +1,582 @@
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::Range;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+
+use pulldown_cmark::{Event, Options, Parser, Tag};
+
+pub use self::external::{ExternalRenderer, Framing, PersistentRenderer};
+pub use self::fences::CodeBlockHandler;
+pub use self::headings::Heading;
+
+use self::blocks::{Block, BlockCache, BlockInfo, Footnotes, SourceMap};
+use self::fences::Fences;
+use self::headings::Headings;
+#[cfg(feature = "server-highlighting")]
+use self::highlight::Highlighter;
+use self::transform::Transforms;
+
+mod blocks;
+mod external;
+mod fences;
+mod headings;
+#[cfg(feature = "server-highlighting")]
+mod highlight;
+mod math;
+mod transform;
+
+/// A markdown to HTML converter.
+///
+/// aurelius ships with [`MarkdownRenderer`], which renders markdown in-process, and
+/// [`ExternalRenderer`] and [`PersistentRenderer`], which delegate to an external program.
+/// Implement this trait to plug in a different renderer with [`Server::set_renderer`].
+///
+/// # Source positions
+///
+/// To map the preview back to the markdown, renderers may annotate block elements with a
+/// CommonMark-style `data-sourcepos` attribute, such as `<p data-sourcepos="3:1-4:12">`. The
+/// value is the 1-based start line and column, followed by the inclusive end line and column.
+/// [`MarkdownRenderer`] adds these attributes to top-level blocks and list items when
+/// [`RenderOptions::sourcepos`] is set, and `cmark --sourcepos` adds them to every block, so it
+/// can be used as an [`ExternalRenderer`] without changes. Only the start line is required.
+///
+/// [`Server::set_renderer`]: crate::Server::set_renderer
+pub trait Renderer: Debug + Send {
+    /// Renders markdown as an HTML fragment.
+    fn render(&mut self, markdown: &str) -> Result<String, RenderError>;
+
+    /// Renders markdown as a list of HTML fragments, one for each top-level block.
+    ///
+    /// Concatenating the blocks must produce the same HTML as [`render`](Renderer::render). The
+    /// server compares the blocks against the previous render, and only sends the blocks that
+    /// changed to the browser. The default implementation renders the whole document as a single
+    /// block.
+    fn render_blocks(&mut self, markdown: &str) -> Result<Vec<String>, RenderError> {
+        self.render(markdown).map(|html| vec![html])
+    }
+
+    /// Returns the outline of the most recently rendered markdown.
+    ///
+    /// The server sends the outline to the browser alongside the HTML, where it is shown as a
+    /// sidebar. The slugs of the headings should match their `id` attributes in the HTML. The
+    /// default implementation returns an empty outline.
+    fn outline(&self) -> Vec<Heading> {
+        vec![]
+    }
+}
+
+/// An error that occurred while rendering markdown.
+#[derive(Debug)]
+pub enum RenderError {
+    /// An I/O error occurred while communicating with the renderer.
+    Io(io::Error),
+
+    /// The renderer did not finish within its timeout, and was killed.
+    Timeout(Duration),
+
+    /// The renderer exited unsuccessfully.
+    Failed {
+        /// The exit code of the renderer, or `None` if it was terminated by a signal.
+        code: Option<i32>,
+
+        /// Everything that the renderer printed to stderr.
+        stderr: String,
+    },
+
+    /// A custom renderer failed.
+    Other(Box<dyn Error + Send + Sync>),
+
+    /// The renderer panicked. The message is the panic's payload, if it was a string.
+    Panicked(String),
+}
+
+impl Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::Io(e) => write!(f, "could not communicate with renderer: {}", e),
+            RenderError::Timeout(timeout) => {
+                write!(f, "renderer timed out after {:?}", timeout)
+            }
+            RenderError::Failed { code, stderr } => {
+                match code {
+                    Some(code) => write!(f, "renderer exited with status {}", code)?,
+                    None => write!(f, "renderer was terminated by a signal")?,
+                }
+
+                if !stderr.trim().is_empty() {
+                    write!(f, ": {}", stderr.trim())?;
+                }
+
+                Ok(())
+            }
+            RenderError::Other(e) => write!(f, "{}", e),
+            RenderError::Panicked(message) => write!(f, "renderer panicked: {}", message),
+        }
+    }
+}
+
+impl Error for RenderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RenderError::Io(e) => Some(e),
+            RenderError::Other(e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RenderError {
+    fn from(e: io::Error) -> Self {
+        RenderError::Io(e)
+    }
+}
+
+/// The built-in renderer, powered by [`pulldown_cmark`].
+///
+/// `pulldown-cmark` is an extremely fast, [CommonMark]-compliant parser that is sufficient for
+/// most use-cases.
+///
+/// The renderer remembers each top-level block from the previous render. Blocks that an edit
+/// didn't affect are reused instead of being processed, written and highlighted again, so
+/// re-rendering a large document after a small change costs little more than parsing it.
+///
+/// [`pulldown_cmark`]: https://github.com/raphlinus/pulldown-cmark
+/// [CommonMark]: https://commonmark.org/
+#[derive(Debug, Default)]
+pub struct MarkdownRenderer {
+    options: RenderOptions,
+    #[cfg(feature = "server-highlighting")]
+    highlighter: Option<Highlighter>,
+    blocks: BlockCache<Arc<BlockInfo>>,
+    cache: BlockCache,
+    fences: Fences,
+    transforms: Transforms,
+    outline: Vec<Heading>,
+}
+
+impl MarkdownRenderer {
+    /// Creates a new renderer with the default options.
+    pub fn new() -> Self {
+        MarkdownRenderer::default()
+    }
+
+    /// Creates a new renderer with the given options.
+    pub fn with_options(options: RenderOptions) -> Self {
+        MarkdownRenderer {
+            options,
+            ..MarkdownRenderer::default()
+        }
+    }
+
+    /// Returns the options used by this renderer.
+    pub fn options(&self) -> RenderOptions {
+        self.options
+    }
+
+    /// Sets the options used by this renderer.
+    pub fn set_options(&mut self, options: RenderOptions) {
+        self.options = options;
+        self.blocks.clear();
+        self.cache.clear();
+    }
+
+    /// Enables or disables syntax highlighting of fenced code blocks.
+    ///
+    /// If a theme is given, code blocks in languages known to [`syntect`] are rendered with inline
+    /// styles, so they are highlighted without any client-side JavaScript. The theme may be the
+    /// name of a theme bundled with `syntect` (such as `"base16-ocean.dark"`), or a highlight.js
+    /// theme with a close equivalent (such as `"github"` or `"solarized-dark"`).
+    ///
+    /// By default, code blocks are not highlighted. Requires the `server-highlighting` feature.
+    ///
+    /// [`syntect`]: https://github.com/trishume/syntect
+    #[cfg(feature = "server-highlighting")]
+    pub fn set_syntax_highlighting(&mut self, theme: Option<&str>) {
+        self.highlighter = theme.map(Highlighter::new);
+        self.blocks.clear();
+        self.cache.clear();
+    }
+
+    /// Renders fenced code blocks in a language by piping them through a command.
+    ///
+    /// The command receives the content of each block on stdin, and should print HTML or an SVG
+    /// image on stdout, which replaces the block in the rendered HTML inside a
+    /// `<div class="fence-output">`. The language is the first word of the block's info string.
+    /// Output is cached by the content of the block, so the command only runs when a block
+    /// changes. If the command fails, its error is shown in place of the block.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::process::Command;
+    /// use aurelius::MarkdownRenderer;
+    ///
+    /// let mut dot = Command::new("dot");
+    /// dot.arg("-Tsvg");
+    ///
+    /// let mut renderer = MarkdownRenderer::new();
+    /// renderer.add_fence_command("dot", dot);
+    /// ```
+    pub fn add_fence_command(&mut self, language: impl Into<String>, command: Command) {
+        self.fences.insert(language.into(), command);
+        self.blocks.clear();
+        self.cache.clear();
+    }
+
+    /// Renders fenced code blocks with an in-process handler.
+    ///
+    /// Each fenced code block is offered to the handlers in the order they were added, and the
+    /// first one to return HTML replaces the block. Handlers take precedence over fence commands.
+    /// See [`CodeBlockHandler`] for an example.
+    pub fn add_code_block_handler(&mut self, handler: Box<dyn CodeBlockHandler>) {
+        self.fences.add_handler(handler);
+        self.blocks.clear();
+        self.cache.clear();
+    }
+
+    /// Adds a hook that maps or filters the events of the document before they are written as
+    /// HTML.
+    ///
+    /// The transform is called with each [`Event`] produced by the parser, and returns the event
+    /// to write in its place, or `None` to remove it. Transforms run in the order they were added,
+    /// before math is parsed and before headings, footnotes and fenced code blocks are processed,
+    /// so dropping raw HTML doesn't drop math. Math isn't recognized in text that a transform
+    /// changes. Transforms can rewrite link targets, add CSS classes, drop raw HTML, or implement
+    /// small syntax extensions. A transform that removes an opening tag must also remove its
+    /// closing tag.
+    ///
+    /// Transforms only see the events of the blocks that changed since the previous render, and
+    /// each block is transformed separately, so a transform should return the same events each
+    /// time it sees the same block.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use aurelius::pulldown_cmark::{Event, Tag};
+    /// use aurelius::{MarkdownRenderer, Renderer};
+    ///
+    /// let mut renderer = MarkdownRenderer::new();
+    ///
+    /// // Link to the rendered versions of other markdown files.
+    /// renderer.add_transform(|event| match event {
+    ///     Event::Start(Tag::Link(kind, dest, title)) if dest.ends_with(".md") => {
+    ///         let dest = format!("{}.html", dest.trim_end_matches(".md"));
+    ///         Some(Event::Start(Tag::Link(kind, dest.into(), title)))
+    ///     }
+    ///     event => Some(event),
+    /// });
+    ///
+    /// assert_eq!(
+    ///     renderer.render("[Usage](usage.md)")?,
+    ///     "<p><a href=\"usage.html\">Usage</a></p>\n",
+    /// );
+    /// # Ok::<_, aurelius::RenderError>(())
+    /// ```
+    pub fn add_transform<F>(&mut self, transform: F)
+    where
+        F: for<'a> FnMut(Event<'a>) -> Option<Event<'a>> + Send + 'static,
+    {
+        self.transforms.push(Box::new(transform));
+        self.blocks.clear();
+        self.cache.clear();
+    }
+}
+
+impl Renderer for MarkdownRenderer {
+    fn render(&mut self, markdown: &str) -> Result<String, RenderError> {
+        self.render_blocks(markdown).map(|blocks| blocks.concat())
+    }
+
+    fn render_blocks(&mut self, markdown: &str) -> Result<Vec<String>, RenderError> {
+        let options = self.options;
+        let source_map = SourceMap::new(markdown);
+        let events: Vec<_> = Parser::new_ext(markdown, options.parser_options())
+            .into_offset_iter()
+            .collect();
+
+        // Blocks are written separately so that they can be annotated with their source position,
+        // and so that the server can tell which blocks changed. Processing a block is about as
+        // expensive as writing it, so the headings and footnotes of a block whose source hasn't
+        // changed since the last render are remembered, and it is only processed again if its
+        // HTML changed too. The outline is collected before any blocks are written, since a table
+        // of contents may come before the headings that it lists.
+        let mut footnotes = Footnotes::default();
+        let mut headings = Headings::default();
+        let mut blocks = vec![];
+
+        for block in blocks::split_blocks(&events) {
+            let key = block.key(markdown);
+            let mut processed = None;
+
+            let transforms = &mut self.transforms;
+            let fences = &self.fences;
+
+            let info = self.blocks.get_or_render(key, || {
+                let events = process(transforms, options, markdown, block.events.to_vec());
+                let info = BlockInfo::new(
+                    &Block {
+                        range: block.range.clone(),
+                        events: &events,
+                    },
+                    markdown,
+                    fences,
+                );
+                processed = Some(events);
+                Arc::new(info)
+            });
+
+            let slugs: Vec<_> = info
+                .headings
+                .iter()
+                .map(|heading| {
+                    let line = source_map.line(block.range.start + heading.offset);
+                    headings.add(heading, line)
+                })
+                .collect();
+
+            // Besides its events, the HTML of a block depends on the parts of the rest of the
+            // document that it refers to, and on its position if that's written too.
+            let mut hasher = DefaultHasher::new();
+            (key, &slugs).hash(&mut hasher);
+
+            for name in &info.footnotes {
+                footnotes.number(name).hash(&mut hasher);
+            }
+
+            if options.sourcepos {
+                source_map.position(block.range.start).hash(&mut hasher);
+            }
+
+            blocks.push((block, info, processed, slugs, hasher.finish()));
+        }
+
+        self.outline = headings.into_outline();
+
+        let mut toc = None;
+        let mut rendered = vec![];
+
+        for (block, info, processed, slugs, mut key) in blocks {
+            let toc = if options.toc && info.is_toc {
+                let toc = toc.get_or_insert_with(|| headings::toc_html(&self.outline));
+                let mut hasher = DefaultHasher::new();
+                (key, toc.as_str()).hash(&mut hasher);
+                key = hasher.finish();
+                Some(&*toc)
+            } else {
+                None
+            };
+
+            #[cfg(feature = "server-highlighting")]
+            let highlighter = &self.highlighter;
+            let transforms = &mut self.transforms;
+            let fences = &mut self.fences;
+            let footnotes = &mut footnotes;
+            let source_map = &source_map;
+            let mut rendered_fences = false;
+
+            let html = self.cache.get_or_render(key, || {
+                let Block { range, events } = block;
+                let mut events = processed
+                    .unwrap_or_else(|| process(transforms, options, markdown, events.to_vec()));
+
+                if let Some(toc) = toc {
+                    events = vec![(Event::Html(toc.to_owned().into()), range.clone())];
+                }
+
+                let events: Vec<_> = events
+                    .into_iter()
+                    .map(|(event, range)| match event {
+                        // List items are annotated too, so that long lists can be navigated.
+                        // The parser reports an inverted range for some empty items, so the end
+                        // is clamped to the start.
+                        Event::Start(Tag::Item) if options.sourcepos => {
+                            let range = range.start..range.end.max(range.start);
+                            let sourcepos = source_map.sourcepos(&range);
+                            Event::Html(format!("<li data-sourcepos=\"{}\">", sourcepos).into())
+                        }
+                        event => event,
+                    })
+                    .collect();
+
+                let events = if options.heading_anchors {
+                    headings::add_anchors(events, &slugs)
+                } else {
+                    events
+                };
+                let events = footnotes.replace(events);
+                let events = fences.replace(events);
+                rendered_fences = true;
+
+                let mut html = String::new();
+
+                #[cfg(feature = "server-highlighting")]
+                let events = match highlighter {
+                    Some(highlighter) => highlighter.highlight(events.into_iter()),
+                    None => events,
+                };
+
+                pulldown_cmark::html::push_html(&mut html, events.into_iter());
+
+                if options.sourcepos && info.is_element {
+                    let sourcepos = source_map.sourcepos(&range);
+                    blocks::add_attribute(&mut html, "data-sourcepos", &sourcepos);
+                }
+
+                html
+            });
+
+            // Fence output that a render doesn't use is evicted, so the output of the fences in a
+            // reused block is kept explicitly.
+            if !rendered_fences {
+                self.fences.keep(&info.fences);
+            }
+
+            rendered.push(html);
+        }
+
+        self.blocks.finish();
+        self.cache.finish();
+        self.fences.finish();
+
+        Ok(rendered)
+    }
+
+    fn outline(&self) -> Vec<Heading> {
+        self.outline.clone()
+    }
+}
+
+/// Applies the transforms to the events of a block and parses the math in it.
+fn process<'a>(
+    transforms: &mut Transforms,
+    options: RenderOptions,
+    markdown: &'a str,
+    events: Vec<(Event<'a>, Range<usize>)>,
+) -> Vec<(Event<'a>, Range<usize>)> {
+    let events = transforms.apply(events);
+
+    if options.math {
+        math::parse_math(markdown, events)
+    } else {
+        events
+    }
+}
+
+/// Escapes text for inclusion in HTML.
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Markdown extensions enabled by the built-in renderer.
+///
+/// The defaults enable every extension that [`pulldown_cmark`] supports. Use
+/// [`RenderOptions::commonmark`] to render strictly according to the [CommonMark] spec.
+///
+/// [`pulldown_cmark`]: https://github.com/raphlinus/pulldown-cmark
+/// [CommonMark]: https://commonmark.org/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderOptions {
+    /// Render pipe tables.
+    pub tables: bool,
+
+    /// Render footnote references and definitions.
+    pub footnotes: bool,
+
+    /// Render `~~text~~` as struck-through text.
+    pub strikethrough: bool,
+
+    /// Render `- [ ]` and `- [x]` list items as checkboxes.
+    pub tasklists: bool,
+
+    /// Render `$...$` and `$$...$$` as inline and display math.
+    ///
+    /// Math is emitted as `<span class="math inline">\(...\)</span>` and
+    /// `<span class="math display">\[...\]</span>` elements containing the TeX source, which
+    /// the preview page typesets with KaTeX. If disabled, the preview page finds math by scanning
+    /// the rendered text for delimiters instead, including dollar signs that aren't math.
+    pub math: bool,
+
+    /// Annotate each top-level block and list item with a `data-sourcepos` attribute.
+    ///
+    /// The attribute holds the range of markdown lines and columns that the block was rendered
+    /// from, in the same `start_line:start_column-end_line:end_column` format as
+    /// `cmark --sourcepos`. It is needed for the preview to follow the editor.
+    pub sourcepos: bool,
+
+    /// Give headings GitHub-compatible `id` attributes and permalink anchors.
+    ///
+    /// Slugs are generated from the text of each heading as on GitHub, so links such as
+    /// `[Installation](#installation)` work the same in the preview. A heading's permalink is an
+    /// empty `<a class="anchor">` element at its start, which the preview page shows on hover.
+    pub heading_anchors: bool,
+
+    /// Replace a paragraph containing only `[TOC]` or `[[_TOC_]]` with a table of contents.
+    ///
+    /// The table of contents is a `<nav class="toc">` element containing nested lists of links to
+    /// the headings of the document. The links need [`heading_anchors`](Self::heading_anchors)
+    /// enabled to work.
+    pub toc: bool,
+}
+
+impl RenderOptions {
+    /// Strict CommonMark, with all extensions disabled.
+    pub fn commonmark() -> Self {
+        RenderOptions {
+            tables: false,
+            footnotes: false,
+            strikethrough: false,
+            tasklists: false,
+            math: false,
+            sourcepos: false,
+            heading_anchors: false,
+            toc: false,
+        }
+    }
+
+    /// GitHub Flavored Markdown: tables, strikethrough, task lists, footnotes, math, heading
+    /// anchors and tables of contents.
+    pub fn github() -> Self {
+        RenderOptions {
+            tables: true,
+            footnotes: true,
+            strikethrough: true,
+            tasklists: true,
+            math: true,
+            sourcepos: false,
+            heading_anchors: true,
+            toc: true,
+        }
+    }
+
+    pub(crate) fn parser_options(&self) -> Options {
+        let mut options = Options::empty();
+        options.set(Options::ENABLE_TABLES, self.tables);
+        options.set(Options::ENABLE_FOOTNOTES, self.footnotes);
+        options.set(Options::ENABLE_STRIKETHROUGH, self.strikethrough);
+        options.set(Options::ENABLE_TASKLISTS, self.tasklists);
+        options
+    }
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions::github()
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::ops::Range;
+
+use pulldown_cmark::{Event, Tag};
+
+use super::escape_html;
+use super::fences::Fences;
+use super::headings::{self, BlockHeading};
+
+/// A top-level block of a document, such as a paragraph, list or table.
+#[derive(Debug)]
+pub(crate) struct Block<'a> {
+    /// The source range of the block.
+    pub range: Range<usize>,
+
+    /// The events that make up the block, with their source ranges.
+    pub events: &'a [(Event<'a>, Range<usize>)],
+}
+
+impl Block<'_> {
+    /// Computes a key for the block that changes whenever its events might, without looking at
+    /// every event.
+    ///
+    /// The events of a block are determined by its source, except for the destinations of
+    /// reference links, which may be defined anywhere in the document, so those are included too.
+    pub fn key(&self, source: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        source[self.range.clone()].hash(&mut hasher);
+
+        for (event, _) in self.events {
+            match event {
+                Event::Start(Tag::Link(_, dest, title))
+                | Event::Start(Tag::Image(_, dest, title)) => {
+                    (&**dest, &**title).hash(&mut hasher);
+                }
+                Event::FootnoteReference(name) => name.hash(&mut hasher),
+                _ => {}
+            }
+        }
+
+        hasher.finish()
+    }
+
+    /// Whether the block renders as a single element that attributes can be added to. Raw HTML
+    /// blocks are written verbatim, so they are left alone.
+    pub fn is_element(&self) -> bool {
+        matches!(
+            self.events.first(),
+            Some((Event::Start(_), _)) | Some((Event::Rule, _))
+        )
+    }
+}
+
+/// What a render needs to know about a block before writing any of the document. It is cached by
+/// the key of the block, so that blocks that didn't change don't need to be processed again.
+#[derive(Debug, Clone)]
+pub(crate) struct BlockInfo {
+    /// Whether the block renders as a single element.
+    pub is_element: bool,
+
+    /// Whether the block is a paragraph containing only a table of contents marker.
+    pub is_toc: bool,
+
+    /// The headings in the block, in order.
+    pub headings: Vec<BlockHeading>,
+
+    /// The names of the footnotes referenced or defined in the block, in order.
+    pub footnotes: Vec<String>,
+
+    /// The keys of the fenced code blocks in the block that are rendered by a handler or command.
+    pub fences: Vec<u64>,
+}
+
+impl BlockInfo {
+    /// Collects the information about a block from its events, once math has been parsed and the
+    /// transforms have been applied.
+    pub fn new(block: &Block, source: &str, fences: &Fences) -> Self {
+        BlockInfo {
+            is_element: block.is_element(),
+            is_toc: matches!(block.events.first(), Some((Event::Start(Tag::Paragraph), _)))
+                && headings::is_toc_marker(&source[block.range.clone()]),
+            headings: headings::find(block.events, block.range.start),
+            footnotes: block
+                .events
+                .iter()
+                .filter_map(|(event, _)| match event {
+                    Event::FootnoteReference(name)
+                    | Event::Start(Tag::FootnoteDefinition(name)) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            fences: fences.keys(block.events),
+        }
+    }
+}
+
+/// Splits a document into its top-level blocks.
+///
+/// Consecutive lines of a raw HTML block are kept together in one block.
+pub(crate) fn split_blocks<'a>(events: &'a [(Event<'a>, Range<usize>)]) -> Vec<Block<'a>> {
+    let mut blocks: Vec<Block> = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, (event, range)) in events.iter().enumerate() {
+        let continues_html = depth == 0
+            && matches!(event, Event::Html(_))
+            && blocks
+                .last()
+                .is_some_and(|block| matches!(block.events.last(), Some((Event::Html(_), _))));
+
+        if depth == 0 && !continues_html {
+            start = i;
+            blocks.push(Block {
+                range: range.clone(),
+                events: &[],
+            });
+        }
+
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            _ => {}
+        }
+
+        let block = blocks.last_mut().unwrap();
+        block.range.end = block.range.end.max(range.end);
+        block.events = &events[start..=i];
+    }
+
+    blocks
+}
+
+/// Numbers footnotes across a whole document, replacing them with equivalent HTML.
+///
+/// The HTML writer numbers footnotes in the order that it sees them, so the numbering must be
+/// shared when the blocks of a document are written separately. The output matches that of the
+/// writer.
+#[derive(Debug, Default)]
+pub(crate) struct Footnotes {
+    numbers: HashMap<String, usize>,
+}
+
+impl Footnotes {
+    /// Replaces the footnotes in a block. Blocks must be passed in document order.
+    pub fn replace<'a>(&mut self, events: Vec<Event<'a>>) -> Vec<Event<'a>> {
+        let mut image_depth = 0;
+
+        events
+            .into_iter()
+            .map(|event| match event {
+                Event::Start(Tag::Image(..)) => {
+                    image_depth += 1;
+                    event
+                }
+                Event::End(Tag::Image(..)) => {
+                    image_depth -= 1;
+                    event
+                }
+                // Image descriptions are written as plain alt text.
+                Event::FootnoteReference(name) if image_depth > 0 => {
+                    Event::Text(format!("[{}]", self.number(&name)).into())
+                }
+                Event::FootnoteReference(name) => Event::Html(
+                    format!(
+                        "<sup class=\"footnote-reference\"><a href=\"#{}\">{}</a></sup>",
+                        escape_html(&name),
+                        self.number(&name),
+                    )
+                    .into(),
+                ),
+                Event::Start(Tag::FootnoteDefinition(name)) => Event::Html(
+                    format!(
+                        "<div class=\"footnote-definition\" id=\"{}\">\
+                         <sup class=\"footnote-definition-label\">{}</sup>",
+                        escape_html(&name),
+                        self.number(&name),
+                    )
+                    .into(),
+                ),
+                Event::End(Tag::FootnoteDefinition(_)) => Event::Html("</div>\n".into()),
+                event => event,
+            })
+            .collect()
+    }
+
+    /// Returns the number of a footnote, numbering it if it hasn't been seen yet.
+    pub fn number(&mut self, name: &str) -> usize {
+        if let Some(&number) = self.numbers.get(name) {
+            return number;
+        }
+
+        let number = self.numbers.len() + 1;
+        self.numbers.insert(name.to_owned(), number);
+        number
+    }
+}
+
+/// The HTML of the blocks of the previous render.
+///
+/// Blocks are keyed by a hash of their source and of everything else that their HTML depends
+/// on, such as the slugs of their headings, the numbers of their footnotes and their position in
+/// the document, so a cached block is identical to a fresh render. The source is much cheaper to
+/// hash than the events of the block, so unchanged blocks don't need to be processed at all.
+///
+/// The cache may also hold other values that are expensive to compute for a block, such as the
+/// output of a fence command.
+pub(crate) struct BlockCache<T = String> {
+    /// Each block, with the number of the render that last used it.
+    blocks: HashMap<u64, (T, usize), BuildHasherDefault<KeyHasher>>,
+    renders: usize,
+}
+
+impl<T> Default for BlockCache<T> {
+    fn default() -> Self {
+        BlockCache {
+            blocks: HashMap::default(),
+            renders: 0,
+        }
+    }
+}
+
+/// Hashes the keys of a [`BlockCache`], which are already hashes, by using them as is.
+#[derive(Default)]
+pub(crate) struct KeyHasher(u64);
+
+impl Hasher for KeyHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ u64::from(byte);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n;
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl<T: Clone> BlockCache<T> {
+    /// Returns the HTML of a block, rendering it if it was not part of the previous render.
+    pub fn get_or_render(&mut self, key: u64, render: impl FnOnce() -> T) -> T {
+        let renders = self.renders;
+        let (html, used) = self
+            .blocks
+            .entry(key)
+            .or_insert_with(|| (render(), renders));
+
+        *used = renders;
+        html.clone()
+    }
+
+    /// Keeps a block from the previous render without using it, such as the output of a fence
+    /// command in a block whose HTML was reused.
+    pub fn keep(&mut self, key: u64) {
+        if let Some((_, used)) = self.blocks.get_mut(&key) {
+            *used = self.renders;
+        }
+    }
+
+    /// Finishes a render, evicting the blocks that it didn't use.
+    pub fn finish(&mut self) {
+        let renders = self.renders;
+        self.blocks.retain(|_, (_, used)| *used == renders);
+        self.renders += 1;
+    }
+
+    /// Evicts all blocks, such as when the options that they were rendered with change.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+}
+
+impl<T> Debug for BlockCache<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("blocks", &self.blocks.len())
+            .finish()
+    }
+}
+
+/// Converts byte offsets in a document to line and column numbers.
+#[derive(Debug)]
+pub(crate) struct SourceMap<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+
+        SourceMap {
+            source,
+            line_starts,
+        }
+    }
+
+    /// Formats a source range as a CommonMark `data-sourcepos` value,
+    /// `start_line:start_column-end_line:end_column`.
+    ///
+    /// Lines and columns are 1-based, columns are counted in bytes, and the end is inclusive.
+    /// Trailing whitespace in the range, such as the newline after a block, is not included.
+    /// Ranges that are inverted or extend past the end of the source are clamped.
+    pub fn sourcepos(&self, range: &Range<usize>) -> String {
+        let start = range.start.min(self.source.len());
+        let end = range.end.clamp(start, self.source.len());
+        let len = self
+            .source
+            .get(start..end)
+            .map_or(0, |source| source.trim_end().len())
+            .max(1);
+        let (start_line, start_column) = self.position(start);
+        let (end_line, end_column) = self.position(start + len - 1);
+
+        format!(
+            "{}:{}-{}:{}",
+            start_line, start_column, end_line, end_column
+        )
+    }
+
+    /// Returns the 1-based line that a byte offset is on.
+    pub fn line(&self, offset: usize) -> usize {
+        self.position(offset).0
+    }
+
+    /// Returns the 1-based line and column of a byte offset.
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+
+        (line + 1, offset - self.line_starts[line] + 1)
+    }
+}
+
+/// Adds an attribute to the first tag in `html`.
+pub(crate) fn add_attribute(html: &mut String, name: &str, value: &str) {
+    let tag = match html.find('<') {
+        Some(offset) => offset + 1,
+        None => return,
+    };
+
+    let name_len = html[tag..]
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(html.len() - tag);
+
+    if name_len > 0 {
+        let attribute = format!(" {}=\"{}\"", name, escape_html(value));
+        html.insert_str(tag + name_len, &attribute);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use pulldown_cmark::{html, Event, Options, Parser, Tag};
+
+    use super::SourceMap;
+    use crate::render::{MarkdownRenderer, RenderOptions, Renderer};
+
+    #[test]
+    fn split_blocks() {
+        let markdown = "# Title\n\n- a\n- b\n\n<div>\nhi\n</div>\n\n---\n";
+        let events: Vec<_> = Parser::new_ext(markdown, Options::empty())
+            .into_offset_iter()
+            .collect();
+
+        let map = SourceMap::new(markdown);
+        let blocks = super::split_blocks(&events)
+            .into_iter()
+            .map(|block| (map.sourcepos(&block.range), block.is_element()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            blocks,
+            [
+                (String::from("1:1-1:7"), true),
+                (String::from("3:1-4:3"), true),
+                (String::from("6:1-8:6"), false),
+                (String::from("10:1-10:3"), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn add_attribute() {
+        let mut html = String::from("<ol start=\"2\">\n");
+        super::add_attribute(&mut html, "data-sourcepos", "3:1-3:4");
+        assert_eq!(html, "<ol data-sourcepos=\"3:1-3:4\" start=\"2\">\n");
+    }
+
+    #[test]
+    fn render_sourcepos() {
+        let mut renderer = MarkdownRenderer::with_options(RenderOptions {
+            sourcepos: true,
+            ..RenderOptions::github()
+        });
+
+        assert_eq!(
+            renderer.render("# Title\n\nSome\ntext\n").unwrap(),
+            "<h1 data-sourcepos=\"1:1-1:7\" id=\"title\">\
+             <a class=\"anchor\" aria-hidden=\"true\" href=\"#title\"></a>Title</h1>\n\
+             <p data-sourcepos=\"3:1-4:4\">Some\ntext</p>\n"
+        );
+        assert_eq!(
+            renderer.render("- a\n- b\n  c\n").unwrap(),
+            "<ul data-sourcepos=\"1:1-3:3\">\n<li data-sourcepos=\"1:1-1:3\">a</li>\n\
+             <li data-sourcepos=\"2:1-3:3\">b\nc</li>\n</ul>\n"
+        );
+    }
+
+    #[test]
+    fn render_sourcepos_empty_items() {
+        let mut renderer = MarkdownRenderer::with_options(RenderOptions {
+            sourcepos: true,
+            ..RenderOptions::github()
+        });
+
+        // The parser reports inverted ranges for some empty list items.
+        for markdown in &["*\n\n1. - [ ] ", ">1. \n1.  - [ ] \r\n- [ ] |---|#"] {
+            let expected = MarkdownRenderer::new().render(markdown).unwrap();
+            let html = renderer.render(markdown).unwrap();
+            assert!(html.contains("data-sourcepos"));
+            assert_eq!(expected.matches("<li").count(), html.matches("<li").count());
+        }
+    }
+
+    #[test]
+    fn sourcepos_out_of_range() {
+        let map = SourceMap::new("ab\ncd\n");
+
+        assert_eq!(map.sourcepos(&Range { start: 4, end: 1 }), "2:2-2:2");
+        assert_eq!(map.sourcepos(&(3..100)), "2:1-2:2");
+        assert_eq!(map.sourcepos(&(100..100)), "3:1-3:1");
+    }
+
+    #[test]
+    fn render_blocks_like_writer() {
+        let markdown = "Text[^b] and[^a].\n\n> quote[^b]\n\n![alt[^a]](x.png)\n\n\
+                        [^a]: A.\n\n[^b]: B.\n\n<div>\nraw\n</div>\n\n---\n";
+
+        let mut expected = String::new();
+        html::push_html(&mut expected, Parser::new_ext(markdown, Options::all()));
+
+        let mut renderer = MarkdownRenderer::new();
+        assert_eq!(renderer.render(markdown).unwrap(), expected);
+    }
+
+    #[test]
+    fn cached_render_matches_fresh_render() {
+        let options = RenderOptions {
+            sourcepos: true,
+            ..RenderOptions::github()
+        };
+        let mut renderer = MarkdownRenderer::with_options(options);
+
+        for markdown in &[
+            "# Title\n\nText[^a] and [link][r].\n\n[^a]: Note.\n\n[r]: http://a.com\n",
+            "# Title\n\nText[^a] and [link][r].\n\n[^a]: Note.\n\n[r]: http://b.com\n",
+            "# Title\n\nMore[^b].\n\nText[^a] and [link][r].\n\n[^a]: Note.\n\n[r]: http://b.com\n",
+            "\n# Title\n\nMore[^b].\n\nText[^a] and [link][r].\n\n[^a]: Note.\n",
+            "[TOC]\n\n# Title\n\n- a\n- b\n\n# Title\n\nText\n\nText\n",
+            "[TOC]\n\n# Intro\n\n# Title\n\n- a\n- b\n\n# Title\n\nText\n\nText\n",
+            "[TOC]\n\n# Intro\n\n# Title\n\n\n- a\n- b\n\n# Title\n\nText\n\nText\n",
+        ] {
+            let expected = MarkdownRenderer::with_options(options).render(markdown);
+            assert_eq!(renderer.render(markdown).unwrap(), expected.unwrap());
+        }
+    }
+
+    #[test]
+    fn unchanged_blocks_not_processed() {
+        let mut renderer = MarkdownRenderer::with_options(RenderOptions {
+            sourcepos: true,
+            ..RenderOptions::github()
+        });
+
+        let paragraphs = Arc::new(AtomicUsize::new(0));
+        let counter = paragraphs.clone();
+        renderer.add_transform(move |event| {
+            if let Event::Start(Tag::Paragraph) = event {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            Some(event)
+        });
+
+        let markdown = (0..100)
+            .map(|i| format!("Paragraph {}\n\n", i))
+            .collect::<String>();
+        renderer.render(&markdown).unwrap();
+        assert_eq!(paragraphs.load(Ordering::SeqCst), 100);
+
+        renderer
+            .render(&markdown.replace("Paragraph 50", "Paragraph fifty"))
+            .unwrap();
+        assert_eq!(paragraphs.load(Ordering::SeqCst), 101);
+
+        // Blocks that moved are written again, since their source positions changed, but blocks
+        // before the edit are still reused.
+        renderer
+            .render(&markdown.replace("Paragraph 50\n", "Paragraph\n50\n"))
+            .unwrap();
+        assert_eq!(paragraphs.load(Ordering::SeqCst), 151);
+    }
+}
@@ -0,0 +1,404 @@
+use std::io::{self, prelude::*, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use log::*;
+
+use super::{RenderError, Renderer};
+
+/// How long an external renderer may run before it is killed, by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check whether an external renderer has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// The longest message that a persistent renderer may send with [`Framing::LengthPrefixed`]. The
+/// length comes from the program, so it can't be trusted to allocate.
+const MAX_FRAME_LEN: usize = 1 << 30;
+
+/// A renderer that delegates to an external program.
+///
+/// The program is spawned once per render. It should expect markdown on stdin and print HTML on
+/// stdout. If the program exits unsuccessfully, the render fails with
+/// [`RenderError::Failed`], which includes anything that the program printed to stderr.
+#[derive(Debug)]
+pub struct ExternalRenderer {
+    command: Command,
+    timeout: Duration,
+}
+
+impl ExternalRenderer {
+    /// Creates a new renderer that will spawn `command` to render markdown.
+    pub fn new(mut command: Command) -> Self {
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        ExternalRenderer {
+            command,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sets how long the program may run before it is killed.
+    ///
+    /// Defaults to 10 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+}
+
+impl Renderer for ExternalRenderer {
+    fn render(&mut self, markdown: &str) -> Result<String, RenderError> {
+        let mut child = self.command.spawn()?;
+        let deadline = Instant::now() + self.timeout;
+
+        // The pipes are serviced on separate threads so that the program can't deadlock by
+        // filling one pipe buffer while we are blocked on another.
+        let mut stdin = child.stdin.take().unwrap();
+        let markdown = markdown.to_owned();
+        thread::spawn(move || {
+            // If the program exits without reading all of its input, the write fails with a
+            // broken pipe. The exit status is more interesting, so ignore the error.
+            let _ = stdin.write_all(markdown.as_bytes());
+        });
+
+        let stdout = read_to_end(child.stdout.take().unwrap());
+        let stderr = read_to_end(child.stderr.take().unwrap());
+
+        let html = match stdout.recv_timeout(remaining(deadline)) {
+            Ok(html) => html?,
+            Err(RecvTimeoutError::Timeout) => return Err(timed_out(&mut child, self.timeout)),
+            Err(RecvTimeoutError::Disconnected) => unreachable!("reader thread panicked"),
+        };
+
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+
+            if Instant::now() >= deadline {
+                return Err(timed_out(&mut child, self.timeout));
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        if !status.success() {
+            let stderr = stderr
+                .recv_timeout(remaining(deadline))
+                .ok()
+                .and_then(Result::ok)
+                .unwrap_or_default();
+
+            return Err(RenderError::Failed {
+                code: status.code(),
+                stderr: String::from_utf8_lossy(&stderr).into_owned(),
+            });
+        }
+
+        String::from_utf8(html)
+            .map_err(|e| RenderError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+}
+
+/// How messages are delimited on the pipes of a [`PersistentRenderer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Each message is terminated by a NUL byte.
+    ///
+    /// Any NUL bytes in the markdown are replaced with U+FFFD before it is sent, as required by
+    /// CommonMark.
+    Nul,
+
+    /// Each message is preceded by its length in bytes, as a decimal number followed by a
+    /// newline.
+    LengthPrefixed,
+}
+
+/// A renderer that keeps a single external program running across renders.
+///
+/// Spawning a process for every render can be expensive: [`pandoc`], for example, takes over
+/// 100ms to start. A `PersistentRenderer` spawns the program once and exchanges documents with
+/// it over stdin and stdout using the chosen [`Framing`]. For each markdown message written to
+/// its stdin, the program must write exactly one HTML message to its stdout.
+///
+/// If the program exits, it is restarted on the next render. If it does not respond within the
+/// timeout, it is killed.
+///
+/// [`pandoc`]: https://pandoc.org/
+#[derive(Debug)]
+pub struct PersistentRenderer {
+    command: Command,
+    framing: Framing,
+    timeout: Duration,
+    process: Option<Process>,
+}
+
+#[derive(Debug)]
+struct Process {
+    child: Child,
+    requests: Sender<Vec<u8>>,
+    responses: Receiver<io::Result<Vec<u8>>>,
+    stderr: Receiver<io::Result<Vec<u8>>>,
+}
+
+impl PersistentRenderer {
+    /// Creates a new renderer that will spawn `command` on the first render.
+    pub fn new(mut command: Command, framing: Framing) -> Self {
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        PersistentRenderer {
+            command,
+            framing,
+            timeout: DEFAULT_TIMEOUT,
+            process: None,
+        }
+    }
+
+    /// Sets how long the program may take to respond to a single document before it is killed.
+    ///
+    /// Defaults to 10 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn spawn(&mut self) -> io::Result<Process> {
+        info!("spawning persistent renderer: {:?}", self.command);
+
+        let mut child = self.command.spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let stderr = read_to_end(child.stderr.take().unwrap());
+
+        let (requests, rx) = crossbeam_channel::unbounded::<Vec<u8>>();
+        let (tx, responses) = crossbeam_channel::unbounded();
+        let framing = self.framing;
+
+        // Like the one-shot renderer, write on a separate thread so that a program that responds
+        // before it has read all of its input can't deadlock.
+        thread::spawn(move || {
+            for message in rx {
+                if stdin.write_all(&message).and_then(|_| stdin.flush()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        thread::spawn(move || {
+            let mut stdout = stdout;
+
+            loop {
+                match read_frame(&mut stdout, framing) {
+                    Ok(Some(frame)) => {
+                        if tx.send(Ok(frame)).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Process {
+            child,
+            requests,
+            responses,
+            stderr,
+        })
+    }
+
+    /// Starts the program if it is not running. Returns `true` if a new process was spawned.
+    fn ensure_running(&mut self) -> io::Result<bool> {
+        if let Some(process) = &mut self.process {
+            match process.child.try_wait()? {
+                Some(status) => {
+                    warn!("persistent renderer exited with {}, restarting", status);
+                }
+                None => return Ok(false),
+            }
+        }
+
+        self.process = Some(self.spawn()?);
+        Ok(true)
+    }
+
+    /// Sends a framed message to the running program and waits for its response.
+    fn exchange(&mut self, message: Vec<u8>) -> Result<Vec<u8>, RenderError> {
+        let timeout = self.timeout;
+        let process = self.process.as_mut().unwrap();
+
+        // If the writer thread has exited, the program has closed stdin. Its response (or lack
+        // thereof) will tell us why.
+        let _ = process.requests.send(message);
+
+        match process.responses.recv_timeout(timeout) {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => {
+                self.process = None;
+                Err(RenderError::Io(e))
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let mut process = self.process.take().unwrap();
+                Err(timed_out(&mut process.child, timeout))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                // The program closed stdout, so it has most likely exited.
+                let mut process = self.process.take().unwrap();
+                let deadline = Instant::now() + timeout;
+
+                let status = loop {
+                    if let Some(status) = process.child.try_wait()? {
+                        break status;
+                    }
+
+                    if Instant::now() >= deadline {
+                        return Err(timed_out(&mut process.child, timeout));
+                    }
+
+                    thread::sleep(POLL_INTERVAL);
+                };
+
+                let stderr = process
+                    .stderr
+                    .recv_timeout(remaining(deadline))
+                    .ok()
+                    .and_then(Result::ok)
+                    .unwrap_or_default();
+
+                Err(RenderError::Failed {
+                    code: status.code(),
+                    stderr: String::from_utf8_lossy(&stderr).into_owned(),
+                })
+            }
+        }
+    }
+}
+
+impl Renderer for PersistentRenderer {
+    fn render(&mut self, markdown: &str) -> Result<String, RenderError> {
+        let mut message = vec![];
+        write_frame(&mut message, self.framing, markdown)?;
+
+        let spawned = self.ensure_running()?;
+
+        let html = match self.exchange(message.clone()) {
+            // The program may have exited after its last response, before we noticed. Give a
+            // fresh process a chance before reporting the failure.
+            Err(RenderError::Failed { .. }) if !spawned => {
+                self.ensure_running()?;
+                self.exchange(message)?
+            }
+            res => res?,
+        };
+
+        String::from_utf8(html)
+            .map_err(|e| RenderError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // The process may have already exited, so ignore errors.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn write_frame(writer: &mut impl Write, framing: Framing, message: &str) -> io::Result<()> {
+    match framing {
+        Framing::Nul => {
+            writer.write_all(message.replace('\0', "\u{FFFD}").as_bytes())?;
+            writer.write_all(b"\0")
+        }
+        Framing::LengthPrefixed => {
+            writeln!(writer, "{}", message.len())?;
+            writer.write_all(message.as_bytes())
+        }
+    }
+}
+
+/// Reads a single message, returning `None` if the stream ended cleanly between messages.
+fn read_frame(reader: &mut impl BufRead, framing: Framing) -> io::Result<Option<Vec<u8>>> {
+    match framing {
+        Framing::Nul => {
+            let mut frame = vec![];
+            reader.read_until(b'\0', &mut frame)?;
+
+            match frame.pop() {
+                Some(b'\0') => Ok(Some(frame)),
+                Some(_) => Err(io::ErrorKind::UnexpectedEof.into()),
+                None => Ok(None),
+            }
+        }
+        Framing::LengthPrefixed => {
+            let mut header = String::new();
+
+            if reader.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+
+            let len = header.trim().parse::<usize>().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid message length {:?}: {}", header, e),
+                )
+            })?;
+
+            if len > MAX_FRAME_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "message length {} exceeds the maximum of {} bytes",
+                        len, MAX_FRAME_LEN
+                    ),
+                ));
+            }
+
+            // Grow the buffer as the message arrives, rather than trusting the length up front.
+            let mut frame = vec![];
+            reader.by_ref().take(len as u64).read_to_end(&mut frame)?;
+
+            if frame.len() < len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            Ok(Some(frame))
+        }
+    }
+}
+
+/// Reads a pipe to completion on a background thread.
+fn read_to_end(mut pipe: impl Read + Send + 'static) -> Receiver<io::Result<Vec<u8>>> {
+    let (tx, rx) = crossbeam_channel::bounded(1);
+
+    thread::spawn(move || {
+        let mut buf = vec![];
+        let res = pipe.read_to_end(&mut buf).map(|_| buf);
+        let _ = tx.send(res);
+    });
+
+    rx
+}
+
+fn remaining(deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(Instant::now())
+}
+
+fn timed_out(child: &mut Child, timeout: Duration) -> RenderError {
+    warn!("renderer timed out after {:?}, killing it", timeout);
+
+    if let Err(e) = child.kill().and_then(|_| child.wait()) {
+        return RenderError::Io(e);
+    }
+
+    RenderError::Timeout(timeout)
+}
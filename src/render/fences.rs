@@ -0,0 +1,337 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::process::Command;
+
+use log::*;
+use pulldown_cmark::{CodeBlockKind, Event, Tag};
+
+use super::blocks::BlockCache;
+use super::{escape_html, ExternalRenderer, Renderer};
+
+/// Renders fenced code blocks in-process, in place of the built-in rendering.
+///
+/// Handlers are registered with [`MarkdownRenderer::add_code_block_handler`] or
+/// [`Server::add_code_block_handler`], and are useful for fences that need more than syntax
+/// highlighting without the overhead of a subprocess, such as drawing a ```` ```chart ```` block as
+/// an SVG image.
+///
+/// Handlers are consulted in the order they were registered, before any fence commands. Like the
+/// output of fence commands, a handler's HTML replaces the block inside a
+/// `<div class="fence-output">`, and is cached by the content of the block, so handlers should
+/// return the same HTML for the same block.
+///
+/// # Example
+///
+/// ```
+/// use aurelius::{CodeBlockHandler, MarkdownRenderer, Renderer};
+///
+/// #[derive(Debug)]
+/// struct Shout;
+///
+/// impl CodeBlockHandler for Shout {
+///     fn render(&self, info: &str, body: &str) -> Option<String> {
+///         if info == "shout" {
+///             Some(format!("<p>{}</p>", body.trim().to_uppercase()))
+///         } else {
+///             None
+///         }
+///     }
+/// }
+///
+/// let mut renderer = MarkdownRenderer::new();
+/// renderer.add_code_block_handler(Box::new(Shout));
+///
+/// assert_eq!(
+///     renderer.render("```shout\nhello\n```\n")?,
+///     "<div class=\"fence-output\" data-lang=\"shout\"><p>HELLO</p></div>\n",
+/// );
+/// # Ok::<_, aurelius::RenderError>(())
+/// ```
+///
+/// [`MarkdownRenderer::add_code_block_handler`]: crate::MarkdownRenderer::add_code_block_handler
+/// [`Server::add_code_block_handler`]: crate::Server::add_code_block_handler
+pub trait CodeBlockHandler: Debug + Send {
+    /// Renders a fenced code block as HTML, given its full info string and its content.
+    ///
+    /// Returns `None` if the handler doesn't handle the block, in which case the next handler is
+    /// consulted, and the block is finally rendered as usual.
+    fn render(&self, info: &str, body: &str) -> Option<String>;
+}
+
+/// Renders fenced code blocks with code block handlers, or by piping them through external
+/// commands, such as `dot -Tsvg` for Graphviz diagrams.
+///
+/// Running a command is slow compared to rendering markdown, so the output is cached by the info
+/// string and content of the block. A command that fails produces an error message in place of
+/// its output, so that a diagram that is being edited doesn't stop the rest of the document from
+/// rendering.
+#[derive(Debug, Default)]
+pub(crate) struct Fences {
+    handlers: Vec<Box<dyn CodeBlockHandler>>,
+    commands: HashMap<String, ExternalRenderer>,
+    cache: BlockCache<Option<String>>,
+}
+
+impl Fences {
+    pub fn add_handler(&mut self, handler: Box<dyn CodeBlockHandler>) {
+        self.handlers.push(handler);
+        self.cache.clear();
+    }
+
+    pub fn insert(&mut self, language: String, command: Command) {
+        self.commands
+            .insert(language, ExternalRenderer::new(command));
+        self.cache.clear();
+    }
+
+    /// Returns the keys of the code blocks in a block that a handler or command renders.
+    pub fn keys(&self, events: &[(Event, Range<usize>)]) -> Vec<u64> {
+        if self.handlers.is_empty() && self.commands.is_empty() {
+            return vec![];
+        }
+
+        let mut keys = vec![];
+        let mut fence: Option<(&str, String)> = None;
+
+        for (event, _) in events {
+            match (&mut fence, event) {
+                (None, Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info)))) => {
+                    let language = info.split_whitespace().next().unwrap_or_default();
+
+                    if !self.handlers.is_empty() || self.commands.contains_key(language) {
+                        fence = Some((info, String::new()));
+                    }
+                }
+                (Some((info, body)), Event::End(Tag::CodeBlock(_))) => {
+                    keys.push(key(info, body));
+                    fence = None;
+                }
+                (Some((_, body)), Event::Text(text)) => body.push_str(text),
+                _ => {}
+            }
+        }
+
+        keys
+    }
+
+    /// Keeps the output of code blocks that weren't rendered again because the HTML of their block
+    /// was reused.
+    pub fn keep(&mut self, keys: &[u64]) {
+        for &key in keys {
+            self.cache.keep(key);
+        }
+    }
+
+    /// Replaces the code blocks in a block that a handler or command renders with their output.
+    pub fn replace<'a>(&mut self, events: Vec<Event<'a>>) -> Vec<Event<'a>> {
+        if self.handlers.is_empty() && self.commands.is_empty() {
+            return events;
+        }
+
+        let mut replaced = Vec::with_capacity(events.len());
+
+        // The events of the fenced code block being collected, if any.
+        let mut fence: Option<Vec<Event<'a>>> = None;
+
+        for event in events {
+            match (&mut fence, event) {
+                (None, Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info)))) => {
+                    let language = info.split_whitespace().next().unwrap_or_default();
+
+                    let start = Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info.clone())));
+
+                    if self.handlers.is_empty() && !self.commands.contains_key(language) {
+                        replaced.push(start);
+                    } else {
+                        fence = Some(vec![start]);
+                    }
+                }
+                (Some(_), Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(info)))) => {
+                    let mut block = fence.take().unwrap();
+
+                    let body = block
+                        .iter()
+                        .filter_map(|event| match event {
+                            Event::Text(text) => Some(&**text),
+                            _ => None,
+                        })
+                        .collect::<String>();
+
+                    match self.render(&info, &body) {
+                        Some(html) => replaced.push(Event::Html(html.into())),
+                        None => {
+                            block.push(Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(info))));
+                            replaced.append(&mut block);
+                        }
+                    }
+                }
+                (Some(block), event) => block.push(event),
+                (None, event) => replaced.push(event),
+            }
+        }
+
+        replaced
+    }
+
+    /// Finishes a render, evicting the output of blocks that it didn't use.
+    pub fn finish(&mut self) {
+        self.cache.finish();
+    }
+
+    /// Renders a block with the first handler that accepts it, or else with the command for its
+    /// language, returning `None` if neither does.
+    fn render(&mut self, info: &str, body: &str) -> Option<String> {
+        let language = info.split_whitespace().next().unwrap_or_default();
+        let handlers = &self.handlers;
+        let commands = &mut self.commands;
+
+        self.cache.get_or_render(key(info, body), || {
+            let output = match handlers.iter().find_map(|handler| handler.render(info, body)) {
+                Some(html) => html,
+                None => match commands.get_mut(language)?.render(body) {
+                    Ok(output) => strip_prolog(&output).to_owned(),
+                    Err(e) => {
+                        warn!("could not render {} block: {}", language, e);
+
+                        return Some(format!(
+                            "<pre class=\"fence-error\" data-lang=\"{}\">{}</pre>\n",
+                            escape_html(language),
+                            escape_html(&e.to_string())
+                        ));
+                    }
+                },
+            };
+
+            Some(format!(
+                "<div class=\"fence-output\" data-lang=\"{}\">{}</div>\n",
+                escape_html(language),
+                output
+            ))
+        })
+    }
+}
+
+/// Computes the cache key of a code block from its info string and content.
+fn key(info: &str, body: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (info, body).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Removes the XML declaration and doctype from the start of an SVG image, since they don't belong
+/// in HTML.
+fn strip_prolog(output: &str) -> &str {
+    let output = output.trim();
+
+    if output.starts_with("<?xml") || output.starts_with("<!") {
+        if let Some(start) = output.find("<svg") {
+            return &output[start..];
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::CodeBlockHandler;
+    use crate::render::{MarkdownRenderer, RenderOptions, Renderer};
+
+    /// Renders `chart` blocks as their info string and line count, counting its renders.
+    #[derive(Debug, Default)]
+    struct Chart {
+        renders: Arc<AtomicUsize>,
+    }
+
+    impl CodeBlockHandler for Chart {
+        fn render(&self, info: &str, body: &str) -> Option<String> {
+            if !info.starts_with("chart") {
+                return None;
+            }
+
+            self.renders.fetch_add(1, Ordering::SeqCst);
+            Some(format!("<svg>{} {}</svg>", info, body.lines().count()))
+        }
+    }
+
+    #[derive(Debug)]
+    struct Fallback;
+
+    impl CodeBlockHandler for Fallback {
+        fn render(&self, _: &str, body: &str) -> Option<String> {
+            Some(format!("<p>{}</p>", body.len()))
+        }
+    }
+
+    #[test]
+    fn code_block_handlers() {
+        let mut renderer = MarkdownRenderer::with_options(RenderOptions::commonmark());
+        renderer.add_code_block_handler(Box::new(Chart::default()));
+
+        assert_eq!(
+            renderer
+                .render("```chart bar\na\nb\n```\n\n```rust\nfn main() {}\n```\n\n    chart\n")
+                .unwrap(),
+            "<div class=\"fence-output\" data-lang=\"chart\"><svg>chart bar 2</svg></div>\n\
+             <pre><code class=\"language-rust\">fn main() {}\n</code></pre>\n\
+             <pre><code>chart\n</code></pre>\n"
+        );
+
+        renderer.add_code_block_handler(Box::new(Fallback));
+
+        assert_eq!(
+            renderer
+                .render("* ```chart\n  a\n  ```\n* ```rust\n  fn main() {}\n  ```\n")
+                .unwrap(),
+            "<ul>\n\
+             <li><div class=\"fence-output\" data-lang=\"chart\"><svg>chart 1</svg></div>\n</li>\n\
+             <li><div class=\"fence-output\" data-lang=\"rust\"><p>13</p></div>\n</li>\n\
+             </ul>\n"
+        );
+    }
+
+    #[test]
+    fn code_block_handler_cached() {
+        let chart = Chart::default();
+        let renders = chart.renders.clone();
+
+        let mut renderer = MarkdownRenderer::new();
+        renderer.add_code_block_handler(Box::new(chart));
+
+        renderer.render("```chart\na\n```\n").unwrap();
+        renderer.render("# Title\n\n```chart\na\n```\n").unwrap();
+        assert_eq!(renders.load(Ordering::SeqCst), 1);
+
+        renderer
+            .render("```chart\na\n```\n\n```chart line\na\n```\n")
+            .unwrap();
+        assert_eq!(renders.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn code_block_handler_cached_when_moved() {
+        let chart = Chart::default();
+        let renders = chart.renders.clone();
+
+        let mut renderer = MarkdownRenderer::with_options(RenderOptions {
+            sourcepos: true,
+            ..RenderOptions::github()
+        });
+        renderer.add_code_block_handler(Box::new(chart));
+
+        // The HTML of the first two renders is identical, so the second render doesn't use the
+        // output of the handler, but the block is written again once it moves.
+        renderer.render("```chart\na\n```\n").unwrap();
+        renderer.render("```chart\na\n```\n").unwrap();
+        let html = renderer.render("Text\n\n```chart\na\n```\n").unwrap();
+
+        assert!(html.contains("<div data-sourcepos=\"3:1-5:3\" class=\"fence-output\""));
+        assert_eq!(renders.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::vec;
+
+use pulldown_cmark::{Event, Tag};
+use serde::{Deserialize, Serialize};
+
+use super::escape_html;
+use super::math;
+
+/// A heading in the outline of a document.
+///
+/// Returned by [`Renderer::outline`](crate::Renderer::outline) and
+/// [`Server::outline`](crate::Server::outline).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Heading {
+    /// The level of the heading, from 1 for `<h1>` to 6 for `<h6>`.
+    pub level: u32,
+
+    /// The text of the heading, without any markup.
+    pub text: String,
+
+    /// The `id` of the heading in the rendered HTML.
+    pub slug: String,
+
+    /// The line of the markdown that the heading starts on, numbered from 1.
+    pub line: usize,
+
+    /// The headings nested under this one, such as the `<h3>`s following an `<h2>`.
+    pub children: Vec<Heading>,
+}
+
+/// A heading in a block, before it has been given a slug.
+#[derive(Debug, Clone)]
+pub(crate) struct BlockHeading {
+    pub level: u32,
+    pub text: String,
+
+    /// The slug of the heading, before it is made unique.
+    pub slug: String,
+
+    /// The offset of the heading from the start of its block, so that it doesn't change when the
+    /// block moves.
+    pub offset: usize,
+}
+
+/// Finds the headings in the events of a block that starts at `start`.
+pub(crate) fn find(events: &[(Event, Range<usize>)], start: usize) -> Vec<BlockHeading> {
+    events
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (event, range))| match event {
+            Event::Start(Tag::Heading(level)) => {
+                let text = text(events[i + 1..].iter().map(|(event, _)| event));
+
+                Some(BlockHeading {
+                    level: *level,
+                    slug: slugify(&text),
+                    text,
+                    offset: range.start.saturating_sub(start),
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Replaces the opening tags of the headings in a block with tags that have `id` attributes and
+/// permalink anchors, given the slugs of the headings in order.
+pub(crate) fn add_anchors<'a>(mut events: Vec<Event<'a>>, slugs: &[String]) -> Vec<Event<'a>> {
+    let mut slugs = slugs.iter();
+
+    for event in &mut events {
+        let (level, slug) = match event {
+            Event::Start(Tag::Heading(level)) => match slugs.next() {
+                Some(slug) => (*level, escape_html(slug)),
+                None => break,
+            },
+            _ => continue,
+        };
+
+        *event = Event::Html(
+            format!(
+                "<h{level} id=\"{slug}\"><a class=\"anchor\" aria-hidden=\"true\" href=\"#{slug}\"></a>",
+                level = level,
+                slug = slug,
+            )
+            .into(),
+        );
+    }
+
+    events
+}
+
+/// Collects the outline of a document, giving each heading a unique slug.
+///
+/// Slugs must be unique across a whole document, so they are shared when the blocks of a document
+/// are written separately.
+#[derive(Debug, Default)]
+pub(crate) struct Headings {
+    slugs: HashMap<String, usize>,
+    outline: Vec<Heading>,
+}
+
+impl Headings {
+    /// Adds a heading that starts on `line` to the outline, returning its slug. Headings must be
+    /// added in document order.
+    pub fn add(&mut self, heading: &BlockHeading, line: usize) -> String {
+        let slug = self.slug(&heading.slug);
+
+        self.outline.push(Heading {
+            level: heading.level,
+            text: heading.text.clone(),
+            slug: slug.clone(),
+            line,
+            children: vec![],
+        });
+
+        slug
+    }
+
+    /// Returns the outline of the document, with headings nested under the closest preceding
+    /// heading of a lower level.
+    pub fn into_outline(self) -> Vec<Heading> {
+        fn nest(headings: &mut Peekable<vec::IntoIter<Heading>>, level: u32) -> Vec<Heading> {
+            let mut nested = vec![];
+
+            while let Some(mut heading) = headings.next_if(|heading| heading.level > level) {
+                heading.children = nest(headings, heading.level);
+                nested.push(heading);
+            }
+
+            nested
+        }
+
+        nest(&mut self.outline.into_iter().peekable(), 0)
+    }
+
+    /// Makes the slug of a heading unique, de-duplicating it like GitHub by appending `-1`, `-2`
+    /// and so on.
+    fn slug(&mut self, original: &str) -> String {
+        let mut slug = original.to_owned();
+
+        while self.slugs.contains_key(&slug) {
+            let count = self.slugs.get_mut(original).unwrap();
+            *count += 1;
+            slug = format!("{}-{}", original, count);
+        }
+
+        self.slugs.insert(slug.clone(), 0);
+        slug
+    }
+}
+
+/// Whether the source of a paragraph is a table of contents marker, `[TOC]` or `[[_TOC_]]`.
+pub(crate) fn is_toc_marker(source: &str) -> bool {
+    let source = source.trim();
+    source.eq_ignore_ascii_case("[toc]") || source.eq_ignore_ascii_case("[[_toc_]]")
+}
+
+/// Writes an outline as a table of contents, a `<nav class="toc">` element containing nested
+/// lists of links to the headings.
+pub(crate) fn toc_html(outline: &[Heading]) -> String {
+    fn push_list(html: &mut String, headings: &[Heading]) {
+        html.push_str("<ul>\n");
+
+        for heading in headings {
+            html.push_str(&format!(
+                "<li><a href=\"#{}\">{}</a>",
+                escape_html(&heading.slug),
+                escape_html(&heading.text)
+            ));
+
+            if !heading.children.is_empty() {
+                html.push('\n');
+                push_list(html, &heading.children);
+            }
+
+            html.push_str("</li>\n");
+        }
+
+        html.push_str("</ul>\n");
+    }
+
+    let mut html = String::from("<nav class=\"toc\">\n");
+
+    if !outline.is_empty() {
+        push_list(&mut html, outline);
+    }
+
+    html.push_str("</nav>\n");
+    html
+}
+
+/// Returns the text content of a heading, given the events that follow its start. Math is
+/// included as its TeX source.
+fn text<'a>(events: impl Iterator<Item = &'a Event<'a>>) -> String {
+    let mut text = String::new();
+
+    for event in events {
+        match event {
+            Event::End(Tag::Heading(_)) => break,
+            Event::Text(t) | Event::Code(t) => text.push_str(t),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::Html(html) => text.extend(math::math_source(html)),
+            _ => {}
+        }
+    }
+
+    text
+}
+
+/// Converts the text of a heading to a slug, following GitHub's rules.
+///
+/// The text is lowercased, every character other than letters, numbers, combining marks,
+/// connector punctuation (such as `_`), hyphens and spaces is removed, and spaces are replaced
+/// with hyphens.
+pub(crate) fn slugify(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            '-' => Some('-'),
+            c if c.is_alphanumeric() || is_mark(c) || is_connector(c) => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether a character is a combining mark that isn't alphabetic, such as a combining accent.
+fn is_mark(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0300}'..='\u{036F}'
+            | '\u{0483}'..='\u{0489}'
+            | '\u{1AB0}'..='\u{1AFF}'
+            | '\u{1DC0}'..='\u{1DFF}'
+            | '\u{20D0}'..='\u{20FF}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FE20}'..='\u{FE2F}'
+    )
+}
+
+/// Whether a character is connector punctuation.
+fn is_connector(c: char) -> bool {
+    matches!(
+        c,
+        '_' | '\u{203F}'
+            | '\u{2040}'
+            | '\u{2054}'
+            | '\u{FE33}'
+            | '\u{FE34}'
+            | '\u{FE4D}'..='\u{FE4F}'
+            | '\u{FF3F}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{slugify, Heading};
+    use crate::render::{MarkdownRenderer, RenderOptions, Renderer};
+
+    fn heading(level: u32, text: &str, line: usize, children: Vec<Heading>) -> Heading {
+        Heading {
+            level,
+            text: text.to_owned(),
+            slug: slugify(text),
+            line,
+            children,
+        }
+    }
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slugify("Installation"), "installation");
+        assert_eq!(slugify("What's new in v2.0?"), "whats-new-in-v20");
+        assert_eq!(
+            slugify("  Leading and  double spaces"),
+            "--leading-and--double-spaces"
+        );
+        assert_eq!(slugify("snake_case & kebab-case"), "snake_case--kebab-case");
+        assert_eq!(slugify("Über Größe"), "über-größe");
+        assert_eq!(slugify("Cafe\u{301} 日本語"), "cafe\u{301}-日本語");
+        assert_eq!(slugify("Emoji 🎉 party"), "emoji--party");
+    }
+
+    #[test]
+    fn render_anchors() {
+        let mut renderer = MarkdownRenderer::new();
+
+        assert_eq!(
+            renderer
+                .render("# Usage\n\n> ## `Usage` *again*\n\n## Usage-1\n\n## Usage\n")
+                .unwrap(),
+            "<h1 id=\"usage\"><a class=\"anchor\" aria-hidden=\"true\" href=\"#usage\"></a>Usage</h1>\n\
+             <blockquote>\n\
+             <h2 id=\"usage-again\"><a class=\"anchor\" aria-hidden=\"true\" href=\"#usage-again\"></a>\
+             <code>Usage</code> <em>again</em></h2>\n\
+             </blockquote>\n\
+             <h2 id=\"usage-1\"><a class=\"anchor\" aria-hidden=\"true\" href=\"#usage-1\"></a>Usage-1</h2>\n\
+             <h2 id=\"usage-2\"><a class=\"anchor\" aria-hidden=\"true\" href=\"#usage-2\"></a>Usage</h2>\n"
+        );
+
+        let mut renderer = MarkdownRenderer::with_options(RenderOptions::commonmark());
+        assert_eq!(renderer.render("# Usage\n").unwrap(), "<h1>Usage</h1>\n");
+    }
+
+    #[test]
+    fn outline() {
+        let mut renderer = MarkdownRenderer::with_options(RenderOptions::commonmark());
+
+        renderer
+            .render("## Intro\n\n# Usage\n\n### Flags\n\nConfig\nFile\n---\n\n# FAQ\n")
+            .unwrap();
+
+        assert_eq!(
+            renderer.outline(),
+            [
+                heading(2, "Intro", 1, vec![]),
+                heading(
+                    1,
+                    "Usage",
+                    3,
+                    vec![
+                        heading(3, "Flags", 5, vec![]),
+                        heading(2, "Config\nFile", 7, vec![]),
+                    ]
+                ),
+                heading(1, "FAQ", 11, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn math_in_headings() {
+        let mut renderer = MarkdownRenderer::new();
+
+        let html = renderer
+            .render("## Cost is $O(n)$\n\n## $a < b$ <b>bold</b>\n")
+            .unwrap();
+        assert!(html.starts_with("<h2 id=\"cost-is-on\">"));
+
+        assert_eq!(
+            renderer.outline(),
+            [
+                heading(2, "Cost is O(n)", 1, vec![]),
+                heading(2, "a < b bold", 3, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn table_of_contents() {
+        let mut renderer = MarkdownRenderer::with_options(RenderOptions {
+            heading_anchors: false,
+            ..RenderOptions::github()
+        });
+
+        assert_eq!(
+            renderer
+                .render("[[_TOC_]]\n\n# A & B\n\n## C\n\n# D\n\n[toc] is a marker.\n")
+                .unwrap(),
+            "<nav class=\"toc\">\n<ul>\n\
+             <li><a href=\"#a--b\">A &amp; B</a>\n<ul>\n<li><a href=\"#c\">C</a></li>\n</ul>\n</li>\n\
+             <li><a href=\"#d\">D</a></li>\n\
+             </ul>\n</nav>\n\
+             <h1>A &amp; B</h1>\n<h2>C</h2>\n<h1>D</h1>\n<p>[toc] is a marker.</p>\n"
+        );
+    }
+}
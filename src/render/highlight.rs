@@ -0,0 +1,115 @@
+use log::*;
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, Tag};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Color, Theme, ThemeSet};
+use syntect::html::{styled_line_to_highlighted_html, IncludeBackground};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+use super::escape_html;
+
+/// Highlights fenced code blocks with [`syntect`], using inline styles.
+///
+/// [`syntect`]: https://github.com/trishume/syntect
+#[derive(Debug)]
+pub(crate) struct Highlighter {
+    syntaxes: SyntaxSet,
+    theme: Theme,
+}
+
+impl Highlighter {
+    /// Creates a highlighter for a theme.
+    ///
+    /// The theme may be the name of a theme bundled with `syntect`, or the name of a highlight.js
+    /// theme that has a close equivalent. Unknown themes fall back to a GitHub-like theme.
+    pub fn new(theme: &str) -> Self {
+        let mut themes = ThemeSet::load_defaults().themes;
+
+        let name = match theme {
+            name if themes.contains_key(name) => name,
+            "solarized-dark" => "Solarized (dark)",
+            "solarized-light" => "Solarized (light)",
+            "ocean" | "base16/ocean" => "base16-ocean.dark",
+            "github" | "github-gist" | "default" => "InspiredGitHub",
+            _ => {
+                warn!("no server-side equivalent for highlight theme {:?}", theme);
+                "InspiredGitHub"
+            }
+        };
+
+        Highlighter {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            theme: themes.remove(name).unwrap(),
+        }
+    }
+
+    /// Replaces fenced code blocks in a language that the highlighter knows with highlighted
+    /// HTML. Other events are passed through unchanged.
+    pub fn highlight<'a>(&self, events: impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+        let mut out = vec![];
+        let mut block: Option<(CowStr<'a>, String)> = None;
+
+        for event in events {
+            match (event, &mut block) {
+                (Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))), None)
+                    if self.syntax(&info).is_some() =>
+                {
+                    block = Some((info, String::new()));
+                }
+                (Event::Text(text), Some((_, code))) => code.push_str(&text),
+                (Event::End(Tag::CodeBlock(_)), Some(_)) => {
+                    let (info, code) = block.take().unwrap();
+                    out.push(Event::Html(self.highlight_block(&info, &code).into()));
+                }
+                (event, _) => out.push(event),
+            }
+        }
+
+        out
+    }
+
+    fn syntax(&self, info: &str) -> Option<&SyntaxReference> {
+        match info.split(' ').next().unwrap() {
+            "" => None,
+            lang => self.syntaxes.find_syntax_by_token(lang),
+        }
+    }
+
+    fn highlight_block(&self, info: &str, code: &str) -> String {
+        let lang = info.split(' ').next().unwrap();
+        let syntax = self.syntax(info).unwrap();
+        let background = self.theme.settings.background.unwrap_or(Color::WHITE);
+
+        let mut html = format!(
+            "<pre style=\"background-color:#{:02x}{:02x}{:02x};\"><code class=\"language-{}\">",
+            background.r,
+            background.g,
+            background.b,
+            escape_html(lang),
+        );
+
+        let mut highlighter = HighlightLines::new(syntax, &self.theme);
+
+        for line in LinesWithEndings::from(code) {
+            let highlighted = highlighter
+                .highlight_line(line, &self.syntaxes)
+                .and_then(|regions| {
+                    styled_line_to_highlighted_html(
+                        &regions,
+                        IncludeBackground::IfDifferent(background),
+                    )
+                });
+
+            match highlighted {
+                Ok(highlighted) => html.push_str(&highlighted),
+                Err(e) => {
+                    warn!("could not highlight {} code: {}", lang, e);
+                    html.push_str(&escape_html(line));
+                }
+            }
+        }
+
+        html.push_str("</code></pre>\n");
+        html
+    }
+}
@@ -0,0 +1,333 @@
+use std::ops::Range;
+
+use pulldown_cmark::{CowStr, Event, Tag};
+
+use super::escape_html;
+
+/// Recognizes `$...$` and `$$...$$` math, replacing it with `<span class="math">` elements.
+///
+/// Math is found in the markdown source rather than in the parsed text, so that backslashes and
+/// other characters that markdown would interpret are passed to TeX unchanged. Delimiters follow
+/// the rules of pandoc's `tex_math_dollars` extension: the opening `$` of inline math must be
+/// followed by a non-space character, and the closing `$` must be preceded by a non-space
+/// character and not followed by a digit. Dollar signs inside code spans, inline HTML, link
+/// destinations and code blocks are never treated as math.
+///
+/// The spans contain the TeX source with `\(...\)` or `\[...\]` delimiters, matching the output
+/// of pandoc's default HTML math method.
+pub(crate) fn parse_math<'a>(
+    source: &'a str,
+    events: Vec<(Event<'a>, Range<usize>)>,
+) -> Vec<(Event<'a>, Range<usize>)> {
+    let mut out = Vec::with_capacity(events.len());
+    let mut inline = vec![];
+    let mut in_code_block = false;
+
+    for (event, range) in events {
+        match &event {
+            Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+            Event::End(Tag::CodeBlock(_)) => in_code_block = false,
+            _ => {}
+        }
+
+        if is_inline(&event) && !in_code_block {
+            inline.push((event, range));
+        } else {
+            replace_math(source, &mut inline, &mut out);
+            out.push((event, range));
+        }
+    }
+
+    replace_math(source, &mut inline, &mut out);
+    out
+}
+
+/// Whether an event is part of a block's inline content.
+fn is_inline(event: &Event) -> bool {
+    use pulldown_cmark::Tag::*;
+
+    match event {
+        Event::Start(tag) | Event::End(tag) => {
+            matches!(
+                tag,
+                Emphasis | Strong | Strikethrough | Link(..) | Image(..)
+            )
+        }
+        Event::Rule => false,
+        _ => true,
+    }
+}
+
+/// Replaces math in a run of inline events, draining them into `out`.
+fn replace_math<'a>(
+    source: &'a str,
+    inline: &mut Vec<(Event<'a>, Range<usize>)>,
+    out: &mut Vec<(Event<'a>, Range<usize>)>,
+) {
+    let spans = find_math(source, inline);
+
+    if spans.is_empty() {
+        out.append(inline);
+        return;
+    }
+
+    let mut spans = spans.into_iter().peekable();
+
+    for (event, range) in inline.drain(..) {
+        let text_range = match &event {
+            Event::Text(text) if is_plain(source, text, &range) => range.clone(),
+            _ => {
+                // Markup inside math is dropped, since its source is already part of the math.
+                let inside = spans
+                    .peek()
+                    .is_some_and(|span| span.start <= range.start && range.end <= span.end);
+
+                if !inside {
+                    out.push((event, range));
+                }
+
+                continue;
+            }
+        };
+
+        // Split the text around any math that starts or ends inside it.
+        let mut start = text_range.start;
+
+        while start < text_range.end {
+            match spans.peek() {
+                Some(span) if span.start < text_range.end => {
+                    let span = span.clone();
+
+                    if start < span.start {
+                        out.push(text(source, start..span.start));
+                    }
+
+                    if span.start >= start {
+                        out.push((Event::Html(math_html(source, &span).into()), span.clone()));
+                    }
+
+                    if span.end <= text_range.end {
+                        spans.next();
+                    }
+
+                    start = span.end;
+                }
+                _ => {
+                    out.push(text(source, start..text_range.end));
+                    start = text_range.end;
+                }
+            }
+        }
+    }
+}
+
+/// Finds the source ranges of math in a run of inline events, including the delimiters.
+fn find_math(source: &str, inline: &[(Event, Range<usize>)]) -> Vec<Range<usize>> {
+    let texts: Vec<Range<usize>> = inline
+        .iter()
+        .filter_map(|(event, range)| match event {
+            Event::Text(text) if is_plain(source, text, range) => Some(range.clone()),
+            _ => None,
+        })
+        .collect();
+
+    let in_text = |range: Range<usize>| {
+        texts
+            .iter()
+            .any(|text| text.start <= range.start && range.end <= text.end)
+    };
+
+    let end = match inline.last() {
+        Some((_, range)) => range.end,
+        None => return vec![],
+    };
+
+    let bytes = source.as_bytes();
+    let mut spans = vec![];
+    let mut pos = match inline.first() {
+        Some((_, range)) => range.start,
+        None => return vec![],
+    };
+
+    while let Some(offset) = source[pos..end].find('$') {
+        let open = pos + offset;
+        pos = open + 1;
+
+        if !in_text(open..open + 1) || (open > 0 && bytes[open - 1] == b'\\') {
+            continue;
+        }
+
+        let span = if bytes.get(open + 1) == Some(&b'$') {
+            pos = open + 2;
+
+            source[open + 2..end]
+                .find("$$")
+                .map(|offset| open..open + 2 + offset + 2)
+                .filter(|span| span.len() > 4 && in_text(span.end - 2..span.end))
+        } else {
+            match source[open + 1..end].chars().next() {
+                Some(c) if !c.is_whitespace() => {
+                    find_inline_close(source, open + 1, end, in_text).map(|close| open..close + 1)
+                }
+                _ => None,
+            }
+        };
+
+        if let Some(span) = span {
+            if inline
+                .iter()
+                .all(|(event, range)| fits(event, range, &span))
+            {
+                pos = span.end;
+                spans.push(span);
+            }
+        }
+    }
+
+    spans
+}
+
+/// Finds the closing `$` of inline math whose content starts at `start`.
+fn find_inline_close(
+    source: &str,
+    start: usize,
+    end: usize,
+    in_text: impl Fn(Range<usize>) -> bool,
+) -> Option<usize> {
+    let bytes = source.as_bytes();
+
+    source[start..end]
+        .match_indices('$')
+        .map(|(offset, _)| start + offset)
+        .find(|&close| {
+            let before = source[..close].chars().next_back().unwrap();
+            let after = source[close + 1..].chars().next();
+
+            close > start
+                && !before.is_whitespace()
+                && bytes[close - 1] != b'\\'
+                && !after.is_some_and(|c| c.is_ascii_digit())
+                && in_text(close..close + 1)
+        })
+}
+
+/// Whether an event can be replaced by, or contain, math spanning `span`.
+///
+/// Math may not start or end inside other markup, such as a code span or a link destination.
+fn fits(event: &Event, range: &Range<usize>, span: &Range<usize>) -> bool {
+    let disjoint = range.end <= span.start || span.end <= range.start;
+    let inside = span.start <= range.start && range.end <= span.end;
+    let contains = range.start <= span.start && span.end <= range.end;
+
+    match event {
+        Event::Text(_) => true,
+        Event::Start(_) | Event::End(_) => disjoint || inside || contains,
+        _ => disjoint || inside,
+    }
+}
+
+/// Whether a text event is a verbatim slice of the source, without entities or escapes.
+fn is_plain(source: &str, text: &str, range: &Range<usize>) -> bool {
+    &source[range.clone()] == text
+}
+
+fn text(source: &str, range: Range<usize>) -> (Event<'_>, Range<usize>) {
+    (Event::Text(CowStr::Borrowed(&source[range.clone()])), range)
+}
+
+fn math_html(source: &str, span: &Range<usize>) -> String {
+    if source[span.clone()].starts_with("$$") {
+        let tex = &source[span.start + 2..span.end - 2];
+        format!(
+            "<span class=\"math display\">\\[{}\\]</span>",
+            escape_html(tex)
+        )
+    } else {
+        let tex = &source[span.start + 1..span.end - 1];
+        format!(
+            "<span class=\"math inline\">\\({}\\)</span>",
+            escape_html(tex)
+        )
+    }
+}
+
+/// Returns the TeX source of math written by [`parse_math`], or `None` if the HTML isn't math.
+pub(crate) fn math_source(html: &str) -> Option<String> {
+    let tex = html
+        .strip_prefix("<span class=\"math inline\">\\(")
+        .and_then(|tex| tex.strip_suffix("\\)</span>"))
+        .or_else(|| {
+            html.strip_prefix("<span class=\"math display\">\\[")
+                .and_then(|tex| tex.strip_suffix("\\]</span>"))
+        })?;
+
+    Some(unescape_html(tex))
+}
+
+/// Reverses [`escape_html`].
+fn unescape_html(html: &str) -> String {
+    html.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use pulldown_cmark::{html, Options, Parser};
+
+    fn render(markdown: &str) -> String {
+        let events = Parser::new_ext(markdown, Options::all())
+            .into_offset_iter()
+            .collect();
+
+        let mut out = String::new();
+        html::push_html(
+            &mut out,
+            super::parse_math(markdown, events)
+                .into_iter()
+                .map(|(event, _)| event),
+        );
+        out
+    }
+
+    #[test]
+    fn inline() {
+        assert_eq!(
+            render("Euler: $e^{i\\pi} + 1 = 0$."),
+            "<p>Euler: <span class=\"math inline\">\\(e^{i\\pi} + 1 = 0\\)</span>.</p>\n"
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            render("$$\n\\{a_1, a_2\\}\n$$"),
+            "<p><span class=\"math display\">\\[\n\\{a_1, a_2\\}\n\\]</span></p>\n"
+        );
+    }
+
+    #[test]
+    fn markup_inside_math() {
+        assert_eq!(
+            render("see $a*b*c$ and *this*"),
+            "<p>see <span class=\"math inline\">\\(a*b*c\\)</span> and <em>this</em></p>\n"
+        );
+        assert!(render("| $x < y$ |\n|---|\n")
+            .contains("<th><span class=\"math inline\">\\(x &lt; y\\)</span></th>"));
+    }
+
+    #[test]
+    fn not_math() {
+        for markdown in &[
+            "It costs $5 and $10.",
+            "Escaped \\$x$.",
+            "Code: `$x$`",
+            "[link $x](http://example.com/$)",
+            "```\n$x$\n```",
+            "$$",
+        ] {
+            assert!(!render(markdown).contains("math"), "{:?}", markdown);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt::{self, Debug};
+use std::ops::Range;
+
+use pulldown_cmark::Event;
+
+type Transform = Box<dyn for<'a> FnMut(Event<'a>) -> Option<Event<'a>> + Send>;
+
+/// Hooks that map or filter the events of a document between parsing and writing HTML.
+///
+/// Transforms see the events as they come from the parser, before math is recognized and before
+/// headings, footnotes and fenced code blocks are processed. Math is found in the source of the
+/// text events that the transforms leave unchanged. Blocks are cached by their source, so the
+/// transforms are only applied to the blocks that changed since the previous render.
+#[derive(Default)]
+pub(crate) struct Transforms {
+    transforms: Vec<Transform>,
+}
+
+impl Transforms {
+    pub fn push(&mut self, transform: Transform) {
+        self.transforms.push(transform);
+    }
+
+    /// Applies each transform in the order they were added. An event that a transform removes is
+    /// not passed to the transforms after it.
+    pub fn apply<'a>(
+        &mut self,
+        events: Vec<(Event<'a>, Range<usize>)>,
+    ) -> Vec<(Event<'a>, Range<usize>)> {
+        if self.transforms.is_empty() {
+            return events;
+        }
+
+        let transforms = &mut self.transforms;
+
+        events
+            .into_iter()
+            .filter_map(|(event, range)| {
+                let event = transforms
+                    .iter_mut()
+                    .try_fold(event, |event, transform| transform(event))?;
+                Some((event, range))
+            })
+            .collect()
+    }
+}
+
+impl Debug for Transforms {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Transforms")
+            .field("transforms", &self.transforms.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pulldown_cmark::{Event, Tag};
+
+    use crate::render::{MarkdownRenderer, RenderOptions, Renderer};
+
+    #[test]
+    fn transforms() {
+        let mut renderer = MarkdownRenderer::with_options(RenderOptions {
+            sourcepos: true,
+            ..RenderOptions::commonmark()
+        });
+
+        renderer.add_transform(|event| match event {
+            Event::Start(Tag::Link(kind, dest, title)) if dest.ends_with(".md") => {
+                let dest = format!("{}.html", dest.trim_end_matches(".md"));
+                Some(Event::Start(Tag::Link(kind, dest.into(), title)))
+            }
+            Event::Html(_) => None,
+            event => Some(event),
+        });
+
+        renderer.add_transform(|event| match event {
+            Event::Text(text) => Some(Event::Text(text.replace(":tada:", "🎉").into())),
+            event => Some(event),
+        });
+
+        assert_eq!(
+            renderer
+                .render(
+                    "See [usage](usage.md) :tada:\n\n<div>\nhidden\n</div>\n\nText <b>bold</b>\n"
+                )
+                .unwrap(),
+            "<p data-sourcepos=\"1:1-1:28\">See <a href=\"usage.html\">usage</a> 🎉</p>\n\
+             <p data-sourcepos=\"7:1-7:16\">Text bold</p>\n"
+        );
+    }
+
+    #[test]
+    fn transforms_run_before_math() {
+        let mut renderer = MarkdownRenderer::new();
+
+        renderer.add_transform(|event| match event {
+            Event::Html(_) => None,
+            event => Some(event),
+        });
+
+        assert_eq!(
+            renderer.render("Euler $e^{i\\pi}=-1$ <b>done</b>").unwrap(),
+            "<p>Euler <span class=\"math inline\">\\(e^{i\\pi}=-1\\)</span> done</p>\n"
+        );
+    }
+
+    #[test]
+    fn stateful_transform() {
+        let mut renderer = MarkdownRenderer::with_options(RenderOptions::commonmark());
+
+        let mut count = 0;
+        renderer.add_transform(move |event| match event {
+            Event::Start(Tag::Heading(level)) => {
+                count += 1;
+                Some(Event::Html(
+                    format!("<h{} class=\"heading-{}\">", level, count).into(),
+                ))
+            }
+            event => Some(event),
+        });
+
+        assert_eq!(
+            renderer.render("# A\n\n## B\n").unwrap(),
+            "<h1 class=\"heading-1\">A</h1>\n<h2 class=\"heading-2\">B</h2>\n"
+        );
+    }
+}
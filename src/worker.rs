@@ -0,0 +1,261 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{self, Debug};
+use std::hash::{Hash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use log::*;
+
+use crate::front_matter::{self, Metadata};
+use crate::id_map::IdMap;
+use crate::render::{Heading, MarkdownRenderer, RenderError, RenderOptions, Renderer};
+use crate::{Config, Signal};
+
+/// The renderers that the server can choose from.
+#[derive(Debug)]
+pub(crate) struct Renderers {
+    /// The built-in renderer. Its settings are controlled through the `Server`.
+    pub markdown: MarkdownRenderer,
+
+    /// A custom renderer that takes precedence over the built-in renderer, if set.
+    pub custom: Option<Box<dyn Renderer>>,
+}
+
+impl Default for Renderers {
+    fn default() -> Self {
+        Renderers {
+            markdown: MarkdownRenderer::with_options(Renderers::default_options()),
+            custom: None,
+        }
+    }
+}
+
+impl Renderers {
+    /// The options of the built-in renderer until they are changed. The preview page needs source
+    /// positions to follow the editor, to jump to the source and to toggle checkboxes.
+    pub fn default_options() -> RenderOptions {
+        RenderOptions {
+            sourcepos: true,
+            ..RenderOptions::github()
+        }
+    }
+
+    /// Renders markdown with the current renderer. Renderers may run code supplied by the host
+    /// program, such as custom renderers, code block handlers and transforms, so a panic is
+    /// reported as an error instead of killing the worker.
+    fn render_blocks(&mut self, markdown: &str) -> Result<Vec<String>, RenderError> {
+        catch_panic(|| match &mut self.custom {
+            Some(renderer) => renderer.render_blocks(markdown),
+            None => self.markdown.render_blocks(markdown),
+        })
+        .unwrap_or_else(|message| Err(RenderError::Panicked(message)))
+    }
+
+    /// Whether the current renderer marks up math itself, rather than leaving the client to find
+    /// math delimiters in its output.
+    fn parses_math(&self) -> bool {
+        self.custom.is_none() && self.markdown.options().math
+    }
+
+    fn outline(&self) -> Vec<Heading> {
+        catch_panic(|| match &self.custom {
+            Some(renderer) => renderer.outline(),
+            None => self.markdown.outline(),
+        })
+        .unwrap_or_else(|message| {
+            error!("renderer panicked while collecting the outline: {}", message);
+            vec![]
+        })
+    }
+}
+
+/// Work for the render worker.
+pub(crate) enum Job {
+    /// Render new markdown.
+    Render(String),
+
+    /// Change the renderers, then render the last markdown again.
+    Configure(Box<dyn FnOnce(&mut Renderers) + Send>),
+
+    /// Exit the worker.
+    Stop,
+}
+
+impl Debug for Job {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Job::Render(markdown) => f.debug_tuple("Render").field(markdown).finish(),
+            Job::Configure(_) => f.debug_tuple("Configure").finish(),
+            Job::Stop => f.debug_tuple("Stop").finish(),
+        }
+    }
+}
+
+/// Counters describing the work done by the server's renderer.
+///
+/// Returned by [`Server::render_stats`](crate::Server::render_stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RenderStats {
+    /// The number of times that markdown was rendered.
+    pub renders: u64,
+
+    /// The number of renders that were skipped because the markdown hadn't changed since the
+    /// last render.
+    pub unchanged_markdown: u64,
+
+    /// The number of renders that weren't sent to clients because neither the HTML, the outline
+    /// nor the metadata had changed.
+    pub unchanged_html: u64,
+}
+
+/// Renders markdown in the background, so that `Server::send` never blocks the caller.
+///
+/// Documents that arrive while a render is in progress replace each other, so only the most
+/// recent one is rendered next. A finished render is always sent to clients, even if a newer
+/// document is waiting, so that the preview keeps up with an editor that changes the document
+/// faster than it can be rendered.
+///
+/// Editors often publish the same document repeatedly, so markdown that is identical to the last
+/// render isn't rendered again, and HTML that is identical to the last broadcast isn't sent.
+#[derive(Debug)]
+pub(crate) struct RenderWorker {
+    pub jobs: Receiver<Job>,
+    pub config: Arc<Mutex<Config>>,
+    pub md_clients: Arc<Mutex<IdMap<Sender<Signal>>>>,
+    pub html: Arc<RwLock<Option<Arc<Vec<String>>>>>,
+    pub outline: Arc<RwLock<Arc<Vec<Heading>>>>,
+    pub metadata: Arc<RwLock<Arc<Metadata>>>,
+    pub math_delimiters: Arc<AtomicBool>,
+    pub stats: Arc<Mutex<RenderStats>>,
+    pub renderers: Renderers,
+    pub markdown: Option<String>,
+
+    /// The hash of the markdown that was last rendered with the current renderers.
+    pub rendered_markdown: Option<u64>,
+
+    /// The hash of the HTML, outline and math delimiter setting that were last sent to clients.
+    pub rendered_html: Option<u64>,
+}
+
+impl RenderWorker {
+    pub fn run(mut self) {
+        while let Ok(job) = self.jobs.recv() {
+            if !self.apply(job) {
+                return;
+            }
+
+            // Wait until the editor has been quiet for the debounce interval, only keeping the
+            // latest document. With no debounce interval, this drains any queued documents.
+            let debounce = self.config.lock().unwrap().debounce;
+
+            loop {
+                match self.jobs.recv_timeout(debounce) {
+                    Ok(job) => {
+                        if !self.apply(job) {
+                            return;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+
+            let markdown = match &self.markdown {
+                Some(markdown) => markdown,
+                None => continue,
+            };
+
+            let markdown_hash = hash(markdown);
+
+            if self.rendered_markdown == Some(markdown_hash) {
+                self.stats.lock().unwrap().unchanged_markdown += 1;
+                continue;
+            }
+
+            // Front matter is never rendered, whichever renderer is used.
+            let (metadata, markdown) = front_matter::split(markdown);
+
+            let html = self.renderers.render_blocks(&markdown);
+            self.stats.lock().unwrap().renders += 1;
+
+            match html {
+                Ok(html) => {
+                    self.rendered_markdown = Some(markdown_hash);
+
+                    let outline = self.renderers.outline();
+                    let math_delimiters = !self.renderers.parses_math();
+                    let html_hash = hash(&(&html, &outline, math_delimiters));
+
+                    if self.rendered_html == Some(html_hash)
+                        && **self.metadata.read().unwrap() == metadata
+                    {
+                        self.stats.lock().unwrap().unchanged_html += 1;
+                        continue;
+                    }
+
+                    self.rendered_html = Some(html_hash);
+                    *self.metadata.write().unwrap() = Arc::new(metadata);
+                    self.math_delimiters
+                        .store(math_delimiters, Ordering::SeqCst);
+                    *self.outline.write().unwrap() = Arc::new(outline);
+                    *self.html.write().unwrap() = Some(Arc::new(html));
+
+                    self.broadcast(|| Signal::NewMarkdown);
+                }
+                Err(e) => {
+                    error!("could not render markdown: {}", e);
+
+                    self.broadcast(|| Signal::Error(e.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Applies a job, returning `false` if the worker should exit.
+    fn apply(&mut self, job: Job) -> bool {
+        match job {
+            Job::Render(markdown) => self.markdown = Some(markdown),
+            Job::Configure(configure) => {
+                let renderers = &mut self.renderers;
+
+                if let Err(message) = catch_panic(|| configure(renderers)) {
+                    error!("could not configure renderer: panicked: {}", message);
+                }
+
+                self.rendered_markdown = None;
+            }
+            Job::Stop => return false,
+        }
+
+        true
+    }
+
+    fn broadcast(&self, signal: impl Fn() -> Signal) {
+        for client in self.md_clients.lock().unwrap().values() {
+            // The client may be disconnecting, in which case it no longer needs updates.
+            let _ = client.send(signal());
+        }
+    }
+}
+
+/// Runs a closure, returning the message of its panic if it panics.
+fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            String::from("unknown panic")
+        }
+    })
+}
+
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
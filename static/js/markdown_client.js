@@ -1,9 +1,20 @@
 document.addEventListener('DOMContentLoaded', function() {
-    function syntaxHighlight() {
-        if (hljs !== undefined) {
-            var codeBlocks = document.querySelectorAll('pre code');
-            for (var i = 0; i < codeBlocks.length; i++) {
-                var codeBlock = codeBlocks[i];
+    // Returns the elements matching a selector in or at any of the given roots.
+    function findAll(roots, selector) {
+        var found = [];
+        roots.forEach(function(root) {
+            if (root.matches(selector)) {
+                found.push(root);
+            }
+            Array.prototype.push.apply(found, root.querySelectorAll(selector));
+        });
+        return found;
+    }
+
+    function syntaxHighlight(roots) {
+        // highlight.js isn't loaded if the server highlights code blocks itself.
+        if (typeof hljs !== 'undefined') {
+            findAll(roots, 'pre code').forEach(function(codeBlock) {
                 hljs.highlightBlock(codeBlock);
 
                 // Since the github css doesn't play nice with highlight.js, we
@@ -12,41 +23,362 @@ document.addEventListener('DOMContentLoaded', function() {
                 codeBlock.parentNode.style.background = (
                     getComputedStyle(codeBlock)
                         .getPropertyValue('background'));
-            }
+            });
         }
     }
 
-    function renderMath() {
-      if (typeof renderMathInElement === 'function') {
-        renderMathInElement(
-            document.getElementById("markdown-preview"),
-            {
+    // Whether the renderer leaves math as text for the client to find, as announced by the
+    // server. The built-in renderer marks up math itself, so dollar signs in its text aren't math.
+    var scanMathDelimiters = false;
+
+    // Typesets math written with delimiters in the text of the given roots, for renderers that
+    // don't mark up math themselves.
+    function renderMathDelimiters(roots) {
+        if (typeof renderMathInElement !== 'function') {
+            return;
+        }
+
+        roots.forEach(function(root) {
+            renderMathInElement(root, {
                 delimiters: [
-                    {left: "$$", right: "$$", display: true},
-                    {left: "\\[", right: "\\]", display: true},
-                    {left: "$", right: "$", display: false},
-                    {left: "\\(", right: "\\)", display: false}
-                ]
+                    {left: '$$', right: '$$', display: true},
+                    {left: '\\[', right: '\\]', display: true},
+                    {left: '$', right: '$', display: false},
+                    {left: '\\(', right: '\\)', display: false}
+                ],
+                // Skip math that has already been typeset, including its TeX annotation.
+                ignoredTags: ['script', 'noscript', 'style', 'textarea', 'pre', 'code', 'option',
+                              'annotation'],
+                ignoredClasses: ['math', 'katex']
+            });
+        });
+    }
+
+    // Typesets the math found by the renderer. Math is marked up as
+    // `<span class="math inline">\(...\)</span>` or `<span class="math display">\[...\]</span>`,
+    // which is also what pandoc produces.
+    function renderMath(roots) {
+        if (typeof katex === 'undefined') {
+            return;
+        }
+
+        findAll(roots, '.math').forEach(function(element) {
+            var tex = element.textContent
+                .replace(/^\s*\\[(\[]/, '')
+                .replace(/\\[)\]]\s*$/, '');
+
+            katex.render(tex, element, {
+                displayMode: element.classList.contains('display'),
+                throwOnError: false
+            });
+        });
+
+        if (scanMathDelimiters) {
+            renderMathDelimiters(roots);
+        }
+    }
+
+    // Whether the preview has a dark background, so that diagrams can match it. The background
+    // comes from the page's stylesheet, falling back to the browser's color scheme.
+    function isDarkTheme() {
+        for (var element = previewWindow; element !== null; element = element.parentElement) {
+            var color = getComputedStyle(element).backgroundColor.match(/[\d.]+/g);
+            if (color !== null && (color.length < 4 || parseFloat(color[3]) > 0)) {
+                var luminance = 0.299 * color[0] + 0.587 * color[1] + 0.114 * color[2];
+                return luminance < 128;
             }
-        );
-      }
+        }
+        return window.matchMedia('(prefers-color-scheme: dark)').matches;
     }
 
+    var mermaidCount = 0;
+
+    function initializeMermaid() {
+        mermaid.initialize({startOnLoad: false, theme: isDarkTheme() ? 'dark' : 'default'});
+    }
+
+    // Draws a diagram from the source stored on its container. Errors are shown in place of the
+    // diagram, followed by the source, so that the diagram can be fixed.
+    function drawMermaid(container) {
+        var id = 'mermaid-' + mermaidCount++;
+        var source = container.getAttribute('data-mermaid-source');
+
+        mermaid.render(id, source).then(function(result) {
+            container.innerHTML = result.svg;
+            if (result.bindFunctions) {
+                result.bindFunctions(container);
+            }
+        }, function(error) {
+            // Mermaid leaves an element behind for failed diagrams.
+            var leftover = document.getElementById('d' + id);
+            if (leftover !== null) {
+                leftover.parentNode.removeChild(leftover);
+            }
+
+            var message = document.createElement('pre');
+            message.className = 'mermaid-error';
+            message.textContent = String(error && error.message || error);
+
+            var code = document.createElement('pre');
+            code.textContent = source;
+
+            container.innerHTML = '';
+            container.appendChild(message);
+            container.appendChild(code);
+        });
+    }
+
+    // Replaces ```mermaid code blocks with diagrams.
+    function renderMermaid(roots) {
+        if (typeof mermaid === 'undefined') {
+            return;
+        }
+
+        var codeBlocks = findAll(roots, 'pre > code.language-mermaid');
+        if (codeBlocks.length === 0) {
+            return;
+        }
+
+        initializeMermaid();
+
+        codeBlocks.forEach(function(code) {
+            var pre = code.parentNode;
+
+            var container = document.createElement('div');
+            container.className = 'mermaid-diagram';
+            container.setAttribute('data-mermaid-source', code.textContent);
+            if (pre.hasAttribute('data-sourcepos')) {
+                container.setAttribute('data-sourcepos', pre.getAttribute('data-sourcepos'));
+            }
+
+            replaceNode(pre, container);
+            drawMermaid(container);
+        });
+    }
+
+    // Redraws the diagrams when the color scheme changes.
+    window.matchMedia('(prefers-color-scheme: dark)').addListener(function() {
+        if (typeof mermaid !== 'undefined') {
+            initializeMermaid();
+            findAll([previewWindow], '.mermaid-diagram').forEach(drawMermaid);
+        }
+    });
+
+    // Scrolls to the last block that starts at or before a line of the markdown, using the
+    // `data-sourcepos` attributes added by the renderer.
+    function scrollToLine(line) {
+        var elements = document.querySelectorAll('#markdown-preview [data-sourcepos]');
+        var target = null;
+
+        for (var i = 0; i < elements.length; i++) {
+            var start = parseInt(elements[i].getAttribute('data-sourcepos'), 10);
+            if (start > line) {
+                break;
+            }
+            target = elements[i];
+        }
+
+        if (target === null) {
+            window.scrollTo(0, 0);
+        } else {
+            target.scrollIntoView({block: 'start'});
+        }
+    }
+
+    // Task list checkboxes are rendered disabled. Enable the ones whose source line is known,
+    // so that they can be toggled from the preview.
+    function enableCheckboxes(roots) {
+        findAll(roots, 'input[type=checkbox]').forEach(function(checkbox) {
+            if (checkbox.closest('li[data-sourcepos]') !== null) {
+                checkbox.disabled = false;
+            }
+        });
+    }
 
-    syntaxHighlight();
-    renderMath();
     var previewWindow = document.getElementById('markdown-preview');
+
+    // The nodes of each block of the preview, in order.
+    var blocks = [];
+
+    // Replaces `remove` blocks starting at `start` with new blocks of HTML, only touching the
+    // nodes of those blocks. Returns the new elements.
+    function applyPatch(start, remove, insert) {
+        var next = null;
+        for (var i = start + remove; i < blocks.length && next === null; i++) {
+            next = blocks[i].length > 0 ? blocks[i][0] : null;
+        }
+
+        blocks.slice(start, start + remove).forEach(function(nodes) {
+            nodes.forEach(function(node) {
+                previewWindow.removeChild(node);
+            });
+        });
+
+        var elements = [];
+        var inserted = insert.map(function(html) {
+            var template = document.createElement('template');
+            template.innerHTML = html;
+
+            var nodes = Array.prototype.slice.call(template.content.childNodes);
+            nodes.forEach(function(node) {
+                previewWindow.insertBefore(node, next);
+                if (node.nodeType === Node.ELEMENT_NODE) {
+                    elements.push(node);
+                }
+            });
+            return nodes;
+        });
+
+        Array.prototype.splice.apply(blocks, [start, remove].concat(inserted));
+        return elements;
+    }
+
+    // Replaces a node of the preview, keeping track of the blocks that it belongs to.
+    function replaceNode(oldNode, newNode) {
+        oldNode.parentNode.replaceChild(newNode, oldNode);
+        blocks.forEach(function(nodes) {
+            var i = nodes.indexOf(oldNode);
+            if (i !== -1) {
+                nodes[i] = newNode;
+            }
+        });
+    }
+
+    var outlineSidebar = document.getElementById('outline-sidebar');
+    var outline = document.getElementById('outline');
+    var outlineToggle = document.getElementById('outline-toggle');
+
+    // Scrolls to a heading by its id, or by its line if the renderer didn't give it an id.
+    function scrollToHeading(heading) {
+        var element = document.getElementById(heading.slug);
+        if (element !== null && previewWindow.contains(element)) {
+            element.scrollIntoView({block: 'start'});
+        } else {
+            scrollToLine(heading.line);
+        }
+    }
+
+    function outlineList(headings) {
+        var list = document.createElement('ul');
+
+        headings.forEach(function(heading) {
+            var link = document.createElement('a');
+            link.href = '#' + heading.slug;
+            link.textContent = heading.text;
+            link.addEventListener('click', function(event) {
+                event.preventDefault();
+                scrollToHeading(heading);
+            });
+
+            var item = document.createElement('li');
+            item.appendChild(link);
+            if (heading.children.length > 0) {
+                item.appendChild(outlineList(heading.children));
+            }
+            list.appendChild(item);
+        });
+
+        return list;
+    }
+
+    // Shows the outline in the sidebar, which is hidden if the document has no headings.
+    function showOutline(headings) {
+        outline.innerHTML = '';
+        if (headings.length > 0) {
+            outline.appendChild(outlineList(headings));
+        }
+        outlineSidebar.hidden = headings.length === 0;
+    }
+
+    outlineToggle.addEventListener('click', function() {
+        var collapsed = outlineSidebar.classList.toggle('collapsed');
+        outlineToggle.setAttribute('aria-expanded', String(!collapsed));
+    });
+
     var webSocketUrl = 'ws://' + window.location.host;
 
-    var socket = new ReconnectingWebSocket(webSocketUrl);
+    var socket = new ReconnectingWebSocket(webSocketUrl, 'aurelius.v2');
     socket.maxReconnectInterval = 5000;
 
+    // The server sends the whole document to new connections.
+    socket.onopen = function(event) {
+        previewWindow.innerHTML = '';
+        blocks = [];
+        showOutline([]);
+    }
+
+    var renderError = document.getElementById('render-error');
+
     socket.onmessage = function(event) {
-        document.getElementById('markdown-preview').innerHTML = event.data;
-        syntaxHighlight();
-        renderMath();
+        var message = JSON.parse(event.data);
+
+        switch (message.type) {
+            case 'patch':
+                renderError.hidden = true;
+                var elements = applyPatch(message.start, message.remove, message.insert);
+                renderMermaid(elements);
+                syntaxHighlight(elements);
+                renderMath(elements);
+                enableCheckboxes(elements);
+                break;
+            case 'math':
+                // Blocks that the next patch leaves alone still need their math typeset.
+                scanMathDelimiters = message.delimiters;
+                if (scanMathDelimiters) {
+                    renderMathDelimiters([previewWindow]);
+                }
+                break;
+            case 'outline':
+                showOutline(message.headings);
+                break;
+            case 'metadata':
+                var title = message.metadata.title;
+                document.title = title ? String(title) : 'Markdown Composer';
+                break;
+            case 'scroll':
+                scrollToLine(message.line);
+                break;
+            case 'error':
+                renderError.textContent = message.message;
+                renderError.hidden = false;
+                break;
+        }
+    }
+
+    // Reports something that happened in the preview to the server.
+    function sendEvent(message) {
+        if (socket.readyState === WebSocket.OPEN) {
+            socket.send(JSON.stringify(message));
+        }
     }
 
+    previewWindow.addEventListener('click', function(event) {
+        var link = event.target.closest('a[href]');
+        if (link !== null) {
+            sendEvent({type: 'link_clicked', href: link.getAttribute('href')});
+        }
+    });
+
+    previewWindow.addEventListener('change', function(event) {
+        var item = event.target.closest('li[data-sourcepos]');
+        if (event.target.type === 'checkbox' && item !== null) {
+            sendEvent({
+                type: 'checkbox_toggled',
+                line: parseInt(item.getAttribute('data-sourcepos'), 10),
+                checked: event.target.checked
+            });
+        }
+    });
+
+    // Double-clicking an element asks the editor to jump to the markdown it came from.
+    previewWindow.addEventListener('dblclick', function(event) {
+        var element = event.target.closest('[data-sourcepos]');
+        if (element !== null) {
+            var line = parseInt(element.getAttribute('data-sourcepos'), 10);
+            sendEvent({type: 'jump_to_source', line: line});
+        }
+    });
+
     socket.onclose = function(event) {
         // Close the browser window.
         window.open('', '_self', '');
@@ -134,3 +134,64 @@ fn static_file_not_found() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[cfg(feature = "bundled-katex")]
+#[test]
+fn bundled_katex() -> Result<(), Box<dyn Error>> {
+    let server = Server::bind("localhost:0")?;
+    let addr = server.addr();
+
+    let text = reqwest::blocking::get(&format!("http://{}", addr))?.text()?;
+    assert!(!text.contains("cdnjs.cloudflare.com"));
+
+    for path in &["katex.min.js", "contrib/auto-render.min.js", "katex.min.css"] {
+        assert!(text.contains(&format!("/__/vendor/katex/{}", path)));
+
+        let res = reqwest::blocking::get(&format!("http://{}/__/vendor/katex/{}", addr, path))?;
+        assert!(res.status().is_success(), "{} is not served", path);
+    }
+
+    // The stylesheet refers to its fonts relative to itself.
+    let res = reqwest::blocking::get(&format!(
+        "http://{}/__/vendor/katex/fonts/KaTeX_Main-Regular.woff2",
+        addr
+    ))?;
+    assert!(res.status().is_success());
+
+    Ok(())
+}
+
+#[cfg(not(feature = "bundled-katex"))]
+#[test]
+fn cdn_katex() -> Result<(), Box<dyn Error>> {
+    let server = Server::bind("localhost:0")?;
+    let addr = server.addr();
+
+    let text = reqwest::blocking::get(&format!("http://{}", addr))?.text()?;
+    assert!(text.contains("cdnjs.cloudflare.com/ajax/libs/KaTeX/0.10.0/katex.min.js"));
+    assert!(text.contains("cdnjs.cloudflare.com/ajax/libs/KaTeX/0.10.0/contrib/auto-render.min.js"));
+    assert!(text.contains("cdnjs.cloudflare.com/ajax/libs/KaTeX/0.10.0/katex.min.css"));
+    assert!(!text.contains("/__/vendor/katex/"));
+
+    let res = reqwest::blocking::get(&format!("http://{}/__/vendor/katex/katex.min.js", addr))?;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[test]
+fn bundled_mermaid() -> Result<(), Box<dyn Error>> {
+    let server = Server::bind("localhost:0")?;
+    let addr = server.addr();
+
+    let text = reqwest::blocking::get(&format!("http://{}", addr))?.text()?;
+    assert!(text.contains("/__/vendor/mermaid/mermaid.min.js"));
+
+    let res = reqwest::blocking::get(&format!(
+        "http://{}/__/vendor/mermaid/mermaid.min.js",
+        addr
+    ))?;
+    assert!(res.status().is_success());
+
+    Ok(())
+}
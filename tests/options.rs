@@ -15,7 +15,7 @@ fn custom_css_url() -> Result<(), Box<dyn Error>> {
     server.set_custom_css(vec![String::from(CSS_URL)])?;
 
     let text = reqwest::blocking::get(&format!("http://{}", server.addr()))?.text()?;
-    assert!(text.contains(&CSS_URL));
+    assert!(text.contains(CSS_URL));
     assert!(!text.contains("github-markdown.css"));
 
     Ok(())
@@ -103,3 +103,69 @@ fn external_renderer() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[test]
+fn custom_renderer() -> Result<(), Box<dyn Error>> {
+    use aurelius::{RenderError, Renderer};
+    use tungstenite::handshake::client::Request;
+
+    #[derive(Debug)]
+    struct Shout;
+
+    impl Renderer for Shout {
+        fn render(&mut self, markdown: &str) -> Result<String, RenderError> {
+            Ok(markdown.to_uppercase())
+        }
+    }
+
+    let mut server = Server::bind("localhost:0")?;
+
+    server.set_renderer(Box::new(Shout));
+
+    let req = Request {
+        url: format!("ws://{}", server.addr()).parse()?,
+        extra_headers: None,
+    };
+
+    let (mut websocket, _) = tungstenite::connect(req)?;
+
+    server.send(String::from("Hello, world!"))?;
+
+    let message = websocket.read_message()?;
+    assert_eq!(message.to_text()?, "HELLO, WORLD!");
+
+    Ok(())
+}
+
+#[cfg(feature = "server-highlighting")]
+#[test]
+fn server_side_highlighting() -> Result<(), Box<dyn Error>> {
+    use tungstenite::handshake::client::Request;
+
+    let mut server = Server::bind("localhost:0")?;
+
+    let text = reqwest::blocking::get(&format!("http://{}", server.addr()))?.text()?;
+    assert!(text.contains("highlight.pack.js"));
+
+    server.set_server_side_highlighting(true);
+
+    let text = reqwest::blocking::get(&format!("http://{}", server.addr()))?.text()?;
+    assert!(!text.contains("highlight.pack.js"));
+    assert!(!text.contains("github.css"));
+
+    let req = Request {
+        url: format!("ws://{}", server.addr()).parse()?,
+        extra_headers: None,
+    };
+
+    let (mut websocket, _) = tungstenite::connect(req)?;
+
+    server.send(String::from("```rust\nfn main() {}\n```"))?;
+
+    let message = websocket.read_message()?;
+    let html = message.to_text()?;
+    assert!(html.starts_with("<pre data-sourcepos=\"1:1-3:3\" style=\"background-color:#"));
+    assert!(html.contains("<code class=\"language-rust\"><span style=\""));
+
+    Ok(())
+}
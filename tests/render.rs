@@ -0,0 +1,221 @@
+#![cfg(not(windows))]
+
+use std::error::Error;
+use std::io;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use matches::assert_matches;
+
+use aurelius::{
+    ExternalRenderer, Framing, MarkdownRenderer, PersistentRenderer, RenderError, Renderer,
+};
+
+fn sh(script: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(script);
+    command
+}
+
+#[test]
+fn external_renderer_large_document() -> Result<(), Box<dyn Error>> {
+    let mut renderer = ExternalRenderer::new(Command::new("cat"));
+
+    // Larger than any reasonable pipe buffer.
+    let markdown = "Lorem ipsum dolor sit amet\n".repeat(100_000);
+
+    assert_eq!(renderer.render(&markdown)?, markdown);
+
+    Ok(())
+}
+
+#[test]
+fn external_renderer_failure() {
+    let mut renderer = ExternalRenderer::new(sh("cat >/dev/null; echo 'oh no' >&2; exit 3"));
+
+    let err = renderer.render("# Hello").unwrap_err();
+
+    match err {
+        RenderError::Failed { code, stderr } => {
+            assert_eq!(code, Some(3));
+            assert_eq!(stderr, "oh no\n");
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn external_renderer_timeout() {
+    let mut renderer = ExternalRenderer::new(sh("exec sleep 5"));
+    renderer.set_timeout(Duration::from_millis(100));
+
+    let start = Instant::now();
+
+    assert_matches!(renderer.render("# Hello"), Err(RenderError::Timeout(_)));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn external_renderer_not_found() {
+    let mut renderer = ExternalRenderer::new(Command::new("/non-existent-renderer"));
+
+    assert_matches!(renderer.render("# Hello"), Err(RenderError::Io(_)));
+}
+
+/// A length-prefixed renderer that numbers its responses, so we can tell whether it was restarted.
+const COUNTING_RENDERER: &str = r#"
+n=0
+while read len; do
+    body=$(dd bs=1 count="$len" 2>/dev/null)
+    n=$((n + 1))
+    out="$n:$body"
+    printf '%s\n%s' "${#out}" "$out"
+done
+"#;
+
+#[test]
+fn persistent_renderer() -> Result<(), Box<dyn Error>> {
+    let mut renderer = PersistentRenderer::new(sh(COUNTING_RENDERER), Framing::LengthPrefixed);
+
+    assert_eq!(renderer.render("a")?, "1:a");
+    assert_eq!(renderer.render("b")?, "2:b");
+    assert_eq!(renderer.render("c")?, "3:c");
+
+    Ok(())
+}
+
+#[test]
+fn persistent_renderer_nul() -> Result<(), Box<dyn Error>> {
+    let mut renderer = PersistentRenderer::new(Command::new("cat"), Framing::Nul);
+
+    assert_eq!(renderer.render("# Hello")?, "# Hello");
+    assert_eq!(renderer.render("# Goodbye")?, "# Goodbye");
+
+    Ok(())
+}
+
+#[test]
+fn persistent_renderer_restart() -> Result<(), Box<dyn Error>> {
+    let script = COUNTING_RENDERER.replace("done", "exit; done");
+    let mut renderer = PersistentRenderer::new(sh(&script), Framing::LengthPrefixed);
+
+    assert_eq!(renderer.render("a")?, "1:a");
+    assert_eq!(renderer.render("b")?, "1:b");
+
+    Ok(())
+}
+
+#[test]
+fn persistent_renderer_huge_length() {
+    let mut renderer = PersistentRenderer::new(
+        sh("read len; echo 1000000000000000; exec sleep 5"),
+        Framing::LengthPrefixed,
+    );
+
+    match renderer.render("# Hello") {
+        Err(RenderError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn persistent_renderer_timeout() -> Result<(), Box<dyn Error>> {
+    let mut renderer = PersistentRenderer::new(sh("exec sleep 5"), Framing::Nul);
+    renderer.set_timeout(Duration::from_millis(100));
+
+    assert_matches!(renderer.render("# Hello"), Err(RenderError::Timeout(_)));
+
+    Ok(())
+}
+
+#[test]
+fn rerender_large_document() -> Result<(), Box<dyn Error>> {
+    let markdown = (0..2000)
+        .map(|i| {
+            format!(
+                "## Section {}\n\nSome *text* with `code`, $x^{}$ and a [link](#top).\n\n\
+                 - a\n- b\n\n```rust\nfn main() {{}}\n```\n\n",
+                i, i
+            )
+        })
+        .collect::<String>();
+    let edit = |i: usize| markdown.replace("Section 1000", &format!("Section 1000.{}", i));
+
+    // Each render is of a document that differs from the previous one in a single block, and the
+    // fastest of several renders is compared.
+    let mut fresh = Duration::MAX;
+    let mut cached = Duration::MAX;
+    let mut renderer = MarkdownRenderer::new();
+    renderer.render(&markdown)?;
+
+    for i in 0..5 {
+        let markdown = edit(i);
+
+        let start = Instant::now();
+        MarkdownRenderer::new().render(&markdown)?;
+        fresh = fresh.min(start.elapsed());
+
+        let start = Instant::now();
+        renderer.render(&markdown)?;
+        cached = cached.min(start.elapsed());
+    }
+
+    assert!(cached * 3 < fresh * 2, "fresh: {:?}, cached: {:?}", fresh, cached);
+
+    Ok(())
+}
+
+#[test]
+fn fence_command() -> Result<(), Box<dyn Error>> {
+    let mut renderer = MarkdownRenderer::new();
+    renderer.add_fence_command(
+        "svg",
+        sh(r#"printf '<?xml version="1.0"?>\n<svg>%s</svg>\n' "$(cat)""#),
+    );
+
+    assert_eq!(
+        renderer.render("```svg {.wide}\n<a> & b\n```\n\n```sh\nls\n```\n")?,
+        "<div class=\"fence-output\" data-lang=\"svg\"><svg><a> & b</svg></div>\n\
+         <pre><code class=\"language-sh\">ls\n</code></pre>\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn fence_command_cached() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let log = dir.path().join("runs");
+
+    let mut renderer = MarkdownRenderer::new();
+    renderer.add_fence_command(
+        "count",
+        sh(&format!("cat >/dev/null; echo run >>'{}'", log.display())),
+    );
+
+    let runs = || std::fs::read_to_string(&log).unwrap().lines().count();
+
+    renderer.render("```count\na\n```\n")?;
+    renderer.render("# Title\n\n```count\na\n```\n")?;
+    assert_eq!(runs(), 1);
+
+    renderer.render("# Title\n\n```count\nb\n```\n\n```count\na\n```\n")?;
+    assert_eq!(runs(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn fence_command_failure() -> Result<(), Box<dyn Error>> {
+    let mut renderer = MarkdownRenderer::new();
+    renderer.add_fence_command("dot", sh("cat >/dev/null; echo 'syntax error' >&2; exit 1"));
+
+    assert_eq!(
+        renderer.render("```dot\ndigraph {\n```\n\nText\n")?,
+        "<pre class=\"fence-error\" data-lang=\"dot\">\
+         renderer exited with status 1: syntax error</pre>\n\
+         <p>Text</p>\n"
+    );
+
+    Ok(())
+}